@@ -8,7 +8,7 @@ import Element exposing (Element, column, el, fill, none, px, row, text)
 import Element.Background as Background
 import Element.Input as Input
 import Element.Region as Region
-import Port exposing (AnalyzeResponse, CalculateResponse, ParseError(..), Response)
+import Port exposing (AnalyzeResponse, CalculateResponse, ParseError, Response, Series(..), Statistics, Suggestion)
 import Session exposing (Session)
 import Style exposing (bgColor, buttonStyle, headingStyle, inputFieldStyle, redColor, textStyle)
 import Util exposing (flip, onEnter)
@@ -21,9 +21,10 @@ expressionPlaceholder =
 
 type alias Model =
     { expr : String
-    , throw : Maybe Int
+    , throw : Maybe CalculateResponse
     , data : Maybe AnalyzeData
-    , error : Maybe ErrorInfo
+    , errors : List ErrorInfo
+    , series : Series
     }
 
 
@@ -32,26 +33,31 @@ type alias AnalyzeData =
     , values : Array Float
     , total : Float
     , max : Float
+    , normalizedExpression : String
+    , statistics : Maybe Statistics
     }
 
 
 type alias ErrorInfo =
     { index : Int
     , description : String
+    , suggestion : Maybe Suggestion
     }
 
 
 init : Model
 init =
-    Model "" Nothing Nothing Nothing
+    Model "" Nothing Nothing [] Exactly
 
 
 type Msg
     = Expr String
     | Throw
+    | SelectSeries Series
     | AnalyzeResponse AnalyzeResponse
     | ThrowResponse CalculateResponse
-    | ErrorResponse ParseError
+    | ErrorResponse (List ParseError)
+    | ApplySuggestion Suggestion
 
 
 responseMsg : Response -> Msg
@@ -79,15 +85,15 @@ responseMsg resp =
 
 
 update : Msg -> Session -> Model -> ( Model, Cmd Msg )
-update msg _ model =
+update msg session model =
     case msg of
         Expr val ->
-            ( { model | expr = val, throw = Nothing, error = Nothing, data = Nothing }
+            ( { model | expr = val, throw = Nothing, errors = [], data = Nothing }
             , if String.isEmpty val then
                 Cmd.none
 
               else
-                Port.analyzeDice val
+                Port.analyzeDice model.series val
             )
 
         Throw ->
@@ -100,36 +106,45 @@ update msg _ model =
                         model.expr
             in
             ( { model | expr = expr, throw = Nothing }
-            , Cmd.batch [ Port.calculateDice expr, Port.analyzeDice expr ]
+            , Cmd.batch [ Port.calculateDice expr, Port.analyzeDice model.series expr ]
+            )
+
+        SelectSeries series ->
+            ( { model | series = series }
+            , if String.isEmpty model.expr then
+                Cmd.none
+
+              else
+                Port.analyzeDice series model.expr
             )
 
         AnalyzeResponse result ->
             ( { model | data = Just result }, Cmd.none )
 
         ThrowResponse result ->
-            ( { model | throw = Just <| result.result }, Cmd.none )
+            ( { model | throw = Just result }, Cmd.none )
 
-        ErrorResponse error ->
-            ( { model | error = Just <| errorToInfo error }, Cmd.none )
+        ErrorResponse errors ->
+            ( { model | errors = List.map errorToInfo errors }, Cmd.none )
+
+        ApplySuggestion suggestion ->
+            let
+                expr =
+                    String.left suggestion.start model.expr
+                        ++ suggestion.replacement
+                        ++ String.dropLeft suggestion.end model.expr
+            in
+            update (Expr expr) session model
 
 
 errorToInfo : ParseError -> ErrorInfo
 errorToInfo error =
-    case error of
-        UnexpectedToken index token ->
-            ErrorInfo index ("Unexpected token '" ++ token ++ "'")
+    ErrorInfo error.start (capitalize error.message) error.suggestion
 
-        BadDie index ->
-            ErrorInfo index "Bad die"
 
-        IllegalExpression index ->
-            ErrorInfo index "Illegal expression"
-
-        UnmatchedParen index ->
-            ErrorInfo index "Unmatched parenthesis"
-
-        EmptyExpression index ->
-            ErrorInfo index "Empty expression"
+capitalize : String -> String
+capitalize str =
+    String.toUpper (String.left 1 str) ++ String.dropLeft 1 str
 
 
 
@@ -156,18 +171,32 @@ view model =
         , Input.button
             (buttonStyle [ Element.centerX, Element.height <| px 50, Element.width <| px 150 ])
             { onPress = Just Throw, label = el [ Element.centerX ] <| text "Throw" }
-        , row (textStyle []) <|
-            case ( model.error, model.throw ) of
-                ( Just e, _ ) ->
-                    [ el [ Element.transparent True ] (text <| String.slice 0 e.index model.expr) -- offset
-                    , text ("^ " ++ e.description) -- error
-                    ]
+        , column [ Element.spacing 4 ] <|
+            case ( model.errors, model.throw ) of
+                ( [], Just t ) ->
+                    [ el (textStyle []) <| text <| "Throw: " ++ t.normalizedExpression ++ " → " ++ t.breakdown ++ " = " ++ String.fromInt t.result ]
 
-                ( Nothing, Just t ) ->
-                    [ el (textStyle []) <| text <| "Throw: " ++ String.fromInt t ]
-
-                ( Nothing, Nothing ) ->
+                ( [], Nothing ) ->
                     [ el (textStyle []) <| text "" ]
+
+                ( errors, _ ) ->
+                    List.map (errorView model.expr) errors
+        , Input.radioRow (textStyle [ Element.spacing 12, Element.centerX ])
+            { onChange = SelectSeries
+            , selected = Just model.series
+            , label = Input.labelHidden "Chances"
+            , options =
+                [ Input.option Exactly <| text "Exactly"
+                , Input.option AtLeast <| text "At least"
+                , Input.option AtMost <| text "At most"
+                ]
+            }
+        , case model.data of
+            Just res ->
+                el (textStyle [ Element.centerX ]) <| text <| "Chances of " ++ res.normalizedExpression
+
+            Nothing ->
+                none
         , case model.data of
             Just res ->
                 dataToCollage res
@@ -175,11 +204,57 @@ view model =
                     |> Element.html
                     |> el [ Element.centerX, Element.width Element.shrink ]
 
+            Nothing ->
+                none
+        , case Maybe.andThen .statistics model.data of
+            Just stats ->
+                statisticsView stats
+
             Nothing ->
                 none
         ]
 
 
+errorView : String -> ErrorInfo -> Element Msg
+errorView expr e =
+    row (textStyle []) <|
+        [ el [ Element.transparent True ] (text <| String.slice 0 e.index expr) -- offset
+        , text ("^ " ++ e.description) -- error
+        ]
+            ++ (case e.suggestion of
+                    Just s ->
+                        [ Input.button [ Element.paddingXY 8 0, Element.alignRight ]
+                            { onPress = Just (ApplySuggestion s), label = text ("(" ++ s.message ++ ")") }
+                        ]
+
+                    Nothing ->
+                        []
+               )
+
+
+statisticsView : Statistics -> Element msg
+statisticsView stats =
+    let
+        line label value =
+            el (textStyle []) <| text <| label ++ ": " ++ value
+
+        ints =
+            String.join ", " << List.map String.fromInt
+    in
+    column [ Element.spacing 4, Element.centerX ]
+        [ line "Average" <| formatFloat stats.mean
+        , line "Standard deviation" <| formatFloat stats.stddev
+        , line "Median" <| String.fromInt stats.median
+        , line "Most likely" <| ints stats.modes
+        , line "Range" <| String.fromInt stats.min ++ " to " ++ String.fromInt stats.max
+        ]
+
+
+formatFloat : Float -> String
+formatFloat v =
+    String.fromFloat <| toFloat (round (v * 100)) / 100
+
+
 collageW : Float
 collageW =
     200
@@ -1,8 +1,11 @@
 port module Port exposing
     ( AnalyzeResponse
     , CalculateResponse
-    , ParseError(..)
+    , ParseError
     , Response(..)
+    , Series(..)
+    , Statistics
+    , Suggestion
     , analyzeDice
     , calculateDice
     , decodeResp
@@ -11,7 +14,7 @@ port module Port exposing
     )
 
 import Array exposing (Array)
-import Json.Decode as Decode exposing (Decoder, andThen, array, at, decodeString, fail, field, float, int, map, map2, map4, oneOf, string)
+import Json.Decode as Decode exposing (Decoder, andThen, array, at, bool, decodeString, fail, field, float, int, lazy, list, map, map2, map3, map4, map5, map6, map8, nullable, oneOf, string, succeed)
 import Json.Encode as Encode
 
 
@@ -30,12 +33,16 @@ port messageReceiver : (String -> msg) -> Sub msg
 
 
 type Response
-    = Calculate (Result ParseError CalculateResponse)
-    | Analyze (Result ParseError AnalyzeResponse)
+    = Calculate (Result (List ParseError) CalculateResponse)
+    | Analyze (Result (List ParseError) AnalyzeResponse)
 
 
 type alias CalculateResponse =
-    { result : Int }
+    { result : Int
+    , normalizedExpression : String
+    , seed : Int
+    , breakdown : String
+    }
 
 
 type alias AnalyzeResponse =
@@ -43,15 +50,43 @@ type alias AnalyzeResponse =
     , values : Array Float
     , total : Float
     , max : Float
+    , normalizedExpression : String
+    , statistics : Maybe Statistics
+    }
+
+
+type alias Statistics =
+    { mean : Float
+    , variance : Float
+    , stddev : Float
+    , median : Int
+    , modes : List Int
+    , min : Int
+    , max : Int
+    , percentiles : List ( Float, Int )
     }
 
 
-type ParseError
-    = UnexpectedToken Int String
-    | BadDie Int
-    | IllegalExpression Int
-    | UnmatchedParen Int
-    | EmptyExpression Int
+{-| Error about the chars `start..end` of the expression
+-}
+type alias ParseError =
+    { kind : String
+    , start : Int
+    , end : Int
+    , expected : List String
+    , message : String
+    , suggestion : Maybe Suggestion
+    }
+
+
+{-| Fix of an error: chars `start..end` of the expression are replaced with `replacement`
+-}
+type alias Suggestion =
+    { message : String
+    , start : Int
+    , end : Int
+    , replacement : String
+    }
 
 
 decodeResp : String -> Result Decode.Error Response
@@ -65,14 +100,14 @@ commandDecoder command =
         "calculate_dice" ->
             map Calculate <|
                 oneOf
-                    [ at [ "error" ] string |> andThen parseErrorDecoder |> map Err
+                    [ field "errors" (list errorDecoder) |> map Err
                     , map Ok calculateDecoder
                     ]
 
         "analyze_dice" ->
             map Analyze <|
                 oneOf
-                    [ at [ "error" ] string |> andThen parseErrorDecoder |> map Err
+                    [ field "errors" (list errorDecoder) |> map Err
                     , map Ok analyzeDecoder
                     ]
 
@@ -82,38 +117,186 @@ commandDecoder command =
 
 calculateDecoder : Decoder CalculateResponse
 calculateDecoder =
-    map CalculateResponse (field "result" int)
+    map4 CalculateResponse
+        (field "result" int)
+        (field "normalized_expression" string)
+        (field "seed" int)
+        (field "trace" traceDecoder)
+
+
+{-| Trace of a throw as a single line, e.g. `[17, ~4~] + 5`. Dropped dice are crossed out with
+`~`, rerolled faces are followed by `→` and explosions are added up with `+`
+-}
+traceDecoder : Decoder String
+traceDecoder =
+    field "kind" string |> andThen traceKindDecoder
+
+
+{-| Operations within operations are parenthesized
+-}
+nestedTraceDecoder : Decoder String
+nestedTraceDecoder =
+    field "kind" string
+        |> andThen
+            (\kind ->
+                if kind == "op" then
+                    traceKindDecoder kind |> map (\t -> "(" ++ t ++ ")")
+
+                else
+                    traceKindDecoder kind
+            )
+
+
+traceKindDecoder : String -> Decoder String
+traceKindDecoder kind =
+    let
+        nested name =
+            field name (lazy (\_ -> nestedTraceDecoder))
+
+        listed =
+            String.join ", " >> (\t -> "[" ++ t ++ "]")
+    in
+    case kind of
+        "num" ->
+            map String.fromInt (field "value" int)
+
+        "dice" ->
+            map listed (field "dice" (list dieRollDecoder))
+
+        "repeated" ->
+            map2 (\count dice -> count ++ " × " ++ listed dice)
+                (nested "count")
+                (field "dice" (list (lazy (\_ -> nestedTraceDecoder))))
+
+        "op" ->
+            map3 (\left op right -> left ++ " " ++ op ++ " " ++ right)
+                (nested "left")
+                (field "op" string)
+                (nested "right")
+
+        "call" ->
+            map3 (\func first second -> func ++ "(" ++ first ++ ", " ++ second ++ ")")
+                (field "func" string)
+                (nested "first")
+                (nested "second")
+
+        "if" ->
+            map2 (\cond branch -> cond ++ " ? " ++ branch) (nested "cond") (nested "branch")
+
+        "attack" ->
+            map4
+                (\natural roll outcome damage ->
+                    "attack("
+                        ++ Maybe.withDefault natural roll
+                        ++ " "
+                        ++ outcome
+                        ++ Maybe.withDefault "" (Maybe.map ((++) ", ") damage)
+                        ++ ")"
+                )
+                (nested "natural")
+                (field "roll" (nullable (lazy (\_ -> nestedTraceDecoder))))
+                (field "outcome" string)
+                (field "damage" (nullable (lazy (\_ -> nestedTraceDecoder))))
+
+        _ ->
+            fail "Unknown trace kind"
+
+
+dieRollDecoder : Decoder String
+dieRollDecoder =
+    map3
+        (\faces rerolled dropped ->
+            let
+                face =
+                    String.concat (List.map (\f -> String.fromInt f ++ "→") rerolled)
+                        ++ String.join "+" (List.map String.fromInt faces)
+            in
+            if dropped then
+                "~" ++ face ++ "~"
+
+            else
+                face
+        )
+        (field "faces" (list int))
+        (field "rerolled" (list int))
+        (field "dropped" bool)
 
 
 analyzeDecoder : Decoder AnalyzeResponse
 analyzeDecoder =
-    map4 AnalyzeResponse
+    map6 AnalyzeResponse
         (at [ "result", "offset" ] int)
         (at [ "result", "values" ] (array float))
         (at [ "result", "total" ] float)
         (at [ "result", "max" ] float)
+        (field "normalized_expression" string)
+        (field "statistics" (nullable statisticsDecoder))
+
+
+statisticsDecoder : Decoder Statistics
+statisticsDecoder =
+    map8 Statistics
+        (field "mean" float)
+        (field "variance" float)
+        (field "stddev" float)
+        (field "median" int)
+        (field "modes" (list int))
+        (field "min" int)
+        (field "max" int)
+        (field "percentiles" (list (map2 Tuple.pair (field "percent" float) (field "value" int))))
+
+
+errorDecoder : Decoder ParseError
+errorDecoder =
+    field "error" string |> andThen parseErrorDecoder
 
 
 parseErrorDecoder : String -> Decoder ParseError
-parseErrorDecoder errkind =
-    case errkind of
-        "unexpected_token" ->
-            map2 UnexpectedToken (field "index" int) (field "token" string)
+parseErrorDecoder kind =
+    let
+        -- Evaluation errors have no span, and no message besides their kind or limit
+        withDefault default decoder =
+            oneOf [ decoder, succeed default ]
+    in
+    map5 (ParseError kind)
+        (withDefault 0 (field "start" int))
+        (withDefault 0 (field "end" int))
+        (withDefault [] (field "expected" (list string)))
+        (oneOf [ field "message" string, limitMessageDecoder, succeed (String.replace "_" " " kind) ])
+        (withDefault Nothing (field "suggestion" (map Just suggestionDecoder)))
+
+
+{-| Message of an evaluation error that goes past a limit, such as the dice of `(d2000)d6`
+-}
+limitMessageDecoder : Decoder String
+limitMessageDecoder =
+    let
+        message limit max =
+            "at most " ++ String.fromInt max ++ " " ++ limitName limit ++ " are allowed"
+    in
+    map2 message (field "limit" string) (field "max" int)
 
-        "bad_die" ->
-            map BadDie (field "index" int)
 
-        "illegal_expression" ->
-            map IllegalExpression (field "index" int)
+limitName : String -> String
+limitName limit =
+    case limit of
+        "faces" ->
+            "faces per die"
 
-        "unmatched_paren" ->
-            map UnmatchedParen (field "index" int)
+        "support" ->
+            "different outcomes"
 
-        "empty_expression" ->
-            map EmptyExpression (field "index" int)
+        other ->
+            other
 
-        _ ->
-            fail "Unexpected error kind"
+
+suggestionDecoder : Decoder Suggestion
+suggestionDecoder =
+    map4 Suggestion
+        (field "message" string)
+        (field "start" int)
+        (field "end" int)
+        (field "replacement" string)
 
 
 
@@ -133,14 +316,34 @@ calculateDice expr =
     sendMessage body
 
 
-analyzeDice : String -> Cmd msg
-analyzeDice expr =
+type Series
+    = Exactly
+    | AtMost
+    | AtLeast
+
+
+analyzeDice : Series -> String -> Cmd msg
+analyzeDice series expr =
     let
         body =
             Encode.object
                 [ ( "command", Encode.string "analyze_dice" )
                 , ( "expression", Encode.string expr )
+                , ( "series", Encode.string <| seriesToString series )
                 ]
                 |> Encode.encode 0
     in
     sendMessage body
+
+
+seriesToString : Series -> String
+seriesToString series =
+    case series of
+        Exactly ->
+            "exactly"
+
+        AtMost ->
+            "at_most"
+
+        AtLeast ->
+            "at_least"
@@ -0,0 +1,95 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use num_bigint::BigUint;
+use std::str::FromStr;
+
+use libdnd::hand::{AnalyzeOptions, Freq, Hand};
+
+/// The double loop every convolution used to be
+fn naive<F: Freq>(left: &[F], right: &[F]) -> Vec<F> {
+    let mut values = vec![F::zero(); left.len() + right.len() - 1];
+    for (ln, lfreq) in left.iter().enumerate() {
+        for (rn, rfreq) in right.iter().enumerate() {
+            values[ln + rn].add_product(lfreq, rfreq);
+        }
+    }
+    values
+}
+
+/// Analysis of `(count)dM` as it used to be: the dice of every count are added one at a time,
+/// each count from scratch
+fn fold(counts: &[(usize, f64)], faces: usize) -> Vec<f64> {
+    let die = vec![1f64; faces];
+    let max = counts.iter().map(|(count, _)| *count).max().unwrap_or(0);
+    let mut values = vec![0f64; max * faces + 1];
+    for (count, freq) in counts.iter() {
+        let sum = (0..*count).fold(vec![1f64], |acc, _| naive(&acc, &die));
+        // Sum of `count` dice starts at `count`
+        for (n, f) in sum.iter().enumerate() {
+            values[count + n] += f * freq;
+        }
+    }
+    values
+}
+
+fn analyzed(expr: &str) -> Vec<f64> {
+    Hand::from_str(expr).unwrap().analyze().unwrap().values
+}
+
+/// Sums of large dice, the frequencies that FFT convolves exactly
+fn convolution(c: &mut Criterion) {
+    let mut group = c.benchmark_group("convolution");
+    for faces in [64usize, 512, 4096].iter() {
+        let values = (
+            analyzed(&format!("2d{}", faces)),
+            analyzed(&format!("d{}", faces)),
+        );
+        group.bench_with_input(BenchmarkId::new("naive", faces), &values, |b, (l, r)| {
+            b.iter(|| naive(l, r))
+        });
+        group.bench_with_input(BenchmarkId::new("fft", faces), &values, |b, (l, r)| {
+            b.iter(|| f64::convolve(l, r))
+        });
+
+        let exact = |values: &[f64]| values.iter().map(|v| BigUint::from(*v as u64)).collect();
+        let values: (Vec<BigUint>, Vec<BigUint>) = (exact(&values.0), exact(&values.1));
+        group.bench_with_input(
+            BenchmarkId::new("naive_exact", faces),
+            &values,
+            |b, (l, r)| b.iter(|| naive(l, r)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("kronecker_exact", faces),
+            &values,
+            |b, (l, r)| b.iter(|| BigUint::convolve(l, r)),
+        );
+    }
+    group.finish();
+}
+
+/// Whole analyses against the fold they used to be
+fn analysis(c: &mut Criterion) {
+    let mut group = c.benchmark_group("analysis");
+    group.sample_size(10);
+    let options = AnalyzeOptions::default();
+    let every_count: Vec<(usize, f64)> = (1..=20).map(|count| (count, 1f64)).collect();
+    let hands = [
+        ("100d100", vec![(100, 1f64)], 100),
+        ("(d20)d20", every_count, 20),
+        ("1000d6", vec![(1000, 1f64)], 6),
+    ];
+    for (expr, counts, faces) in hands.iter() {
+        group.bench_with_input(BenchmarkId::new("fold", expr), expr, |b, _| {
+            b.iter(|| fold(counts, *faces))
+        });
+        group.bench_with_input(BenchmarkId::new("f64", expr), expr, |b, e| {
+            b.iter(|| Hand::from_str(e).unwrap().analyze_with::<f64>(&options))
+        });
+        group.bench_with_input(BenchmarkId::new("exact", expr), expr, |b, e| {
+            b.iter(|| Hand::from_str(e).unwrap().analyze_with::<BigUint>(&options))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, convolution, analysis);
+criterion_main!(benches);
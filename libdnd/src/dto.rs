@@ -1,4 +1,4 @@
-use crate::hand::{FreqGraph, ParseError};
+use crate::hand::{Error, EvalError, Freq, FreqGraph, Hand, ParseError, Statistics, Trace};
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Serialize)]
@@ -15,13 +15,13 @@ where
 #[derive(Serialize)]
 #[serde(tag = "command", rename_all = "snake_case")]
 pub enum Response {
-    CalculateDice(CommandResult<CalculateResponse, ParseError>),
-    AnalyzeDice(CommandResult<AnalyzeResponse, ParseError>),
+    CalculateDice(CommandResult<CalculateResponse, ErrorResponse>),
+    AnalyzeDice(CommandResult<AnalyzeResponse, ErrorResponse>),
     MessageParseError,
 }
 
-impl From<Result<CalculateResponse, ParseError>> for Response {
-    fn from(res: Result<CalculateResponse, ParseError>) -> Self {
+impl From<Result<CalculateResponse, ErrorResponse>> for Response {
+    fn from(res: Result<CalculateResponse, ErrorResponse>) -> Self {
         match res {
             Ok(res) => Response::CalculateDice(CommandResult::Result(res)),
             Err(e) => Response::CalculateDice(CommandResult::Error(e)),
@@ -29,8 +29,8 @@ impl From<Result<CalculateResponse, ParseError>> for Response {
     }
 }
 
-impl From<Result<AnalyzeResponse, ParseError>> for Response {
-    fn from(res: Result<AnalyzeResponse, ParseError>) -> Self {
+impl From<Result<AnalyzeResponse, ErrorResponse>> for Response {
+    fn from(res: Result<AnalyzeResponse, ErrorResponse>) -> Self {
         match res {
             Ok(res) => Response::AnalyzeDice(CommandResult::Result(res)),
             Err(e) => Response::AnalyzeDice(CommandResult::Error(e)),
@@ -38,33 +38,148 @@ impl From<Result<AnalyzeResponse, ParseError>> for Response {
     }
 }
 
+/// Every error of the expression, in the order they appear in it. Only parsing goes on past the
+/// first error, so there is never more than one evaluation error
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub errors: Vec<Error>,
+}
+
+impl From<Vec<ParseError>> for ErrorResponse {
+    fn from(errors: Vec<ParseError>) -> Self {
+        Self {
+            errors: errors.into_iter().map(Error::from).collect(),
+        }
+    }
+}
+
+impl From<EvalError> for ErrorResponse {
+    fn from(e: EvalError) -> Self {
+        Self {
+            errors: vec![e.into()],
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct CalculateResponse {
     pub result: i64,
+    /// Expression as it was parsed, with the implicit multiplications spelled out
+    pub normalized_expression: String,
+    /// Seed the dice were thrown with, the same seed in the request throws the same result
+    pub seed: u64,
+    /// Every die of the throw, and how it adds up to the result
+    pub trace: Trace,
 }
 
 #[derive(Serialize)]
 pub struct AnalyzeResponse {
     pub result: FreqGraphResponse,
+    /// Expression as it was parsed, with the implicit multiplications spelled out
+    pub normalized_expression: String,
+    /// Missing if every outcome was truncated
+    pub statistics: Option<StatisticsResponse>,
+}
+
+impl AnalyzeResponse {
+    /// Statistics are always of the distribution itself, whatever the `series`
+    pub fn new<F: Freq>(
+        hand: &Hand,
+        graph: FreqGraph<F>,
+        series: Series,
+        percentiles: &[f64],
+    ) -> Self {
+        let statistics = graph.statistics().map(|stats| {
+            let percentiles = percentiles
+                .iter()
+                .filter_map(|percent| {
+                    graph.percentile(*percent).map(|value| PercentileResponse {
+                        percent: *percent,
+                        value,
+                    })
+                })
+                .collect();
+            StatisticsResponse::new(stats, percentiles)
+        });
+        // Cumulative frequencies add up to more than the total, chances are still out of it
+        let total = graph.total();
+        let result = match series {
+            Series::Exactly => FreqGraphResponse::new(&graph, &total),
+            Series::AtMost => FreqGraphResponse::new(&graph.cdf(), &total),
+            Series::AtLeast => FreqGraphResponse::new(&graph.survival(), &total),
+        };
+        Self {
+            result,
+            normalized_expression: hand.to_string(),
+            statistics,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatisticsResponse {
+    mean: f64,
+    variance: f64,
+    stddev: f64,
+    median: i64,
+    modes: Vec<i64>,
+    min: i64,
+    max: i64,
+    percentiles: Vec<PercentileResponse>,
+}
+
+impl StatisticsResponse {
+    fn new(stats: Statistics, percentiles: Vec<PercentileResponse>) -> Self {
+        Self {
+            mean: stats.mean,
+            variance: stats.variance,
+            stddev: stats.stddev,
+            median: stats.median,
+            modes: stats.modes,
+            min: stats.min,
+            max: stats.max,
+            percentiles,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PercentileResponse {
+    percent: f64,
+    value: i64,
 }
 
+/// Chances of the outcomes, so `total` is always `1`. Frequencies are only converted to `f64`
+/// here, after they are divided by the exact total
 #[derive(Serialize)]
 pub struct FreqGraphResponse {
     offset: i64,
     values: Vec<f64>,
     max: f64,
     total: f64,
+    truncated: f64,
 }
 
-impl From<FreqGraph> for FreqGraphResponse {
-    fn from(graph: FreqGraph) -> Self {
-        let max = graph.values.iter().fold(0f64, |acc, v| f64::max(acc, *v));
-        let total = graph.values.iter().sum();
+impl<F: Freq> From<FreqGraph<F>> for FreqGraphResponse {
+    fn from(graph: FreqGraph<F>) -> Self {
+        Self::new(&graph, &graph.total())
+    }
+}
+
+impl FreqGraphResponse {
+    /// Chances of the frequencies of `graph` out of `total`
+    fn new<F: Freq>(graph: &FreqGraph<F>, total: &F) -> Self {
+        // Truncated outcomes have no bar of their own, so they never set the scale of the chart
+        let max = graph
+            .values
+            .iter()
+            .fold(F::zero(), |acc, v| if *v > acc { v.clone() } else { acc });
         Self {
             offset: graph.offset,
-            values: graph.values,
-            max,
-            total,
+            values: graph.values.iter().map(|v| v.ratio(total)).collect(),
+            max: max.ratio(total),
+            total: 1f64,
+            truncated: graph.truncated.ratio(total),
         }
     }
 }
@@ -79,4 +194,96 @@ pub enum Request {
 #[derive(Deserialize, Serialize)]
 pub struct Dice {
     pub expression: String,
+    /// Overrides the default explosion depth of the analysis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explosion_depth: Option<u32>,
+    /// Analyzes with exact big integer frequencies instead of `f64`, which is slower
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exact: bool,
+    /// Percentiles to include in the statistics of the analysis
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub percentiles: Vec<f64>,
+    /// Chances that the analysis returns for every outcome
+    #[serde(default)]
+    pub series: Series,
+    /// Seed of the random generator for the throw, picked at random if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Series {
+    /// Chance of exactly the outcome, `P(X = k)`
+    #[default]
+    Exactly,
+    /// `P(X <= k)`
+    AtMost,
+    /// `P(X >= k)`
+    AtLeast,
+}
+
+#[cfg(test)]
+mod test {
+    use num_bigint::BigUint;
+    use std::str::FromStr;
+    use wasm_bindgen_test::*;
+
+    use super::*;
+    use crate::hand::AnalyzeOptions;
+
+    fn response(expr: &str, series: Series, percentiles: &[f64]) -> AnalyzeResponse {
+        let hand = Hand::from_str(expr).unwrap();
+        let graph = hand.analyze().unwrap();
+        AnalyzeResponse::new(&hand, graph, series, percentiles)
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn freq_graph_chances() {
+        let graph = response("10d2!", Series::Exactly, &[]).result;
+        let tallest = graph.values.iter().cloned().fold(0f64, f64::max);
+        assert!(graph.truncated > tallest);
+        assert_eq!(graph.max, tallest);
+        assert_eq!(graph.total, 1f64);
+
+        let hand = Hand::from_str("2d6").unwrap();
+        let exact = hand.analyze_with::<BigUint>(&AnalyzeOptions::default());
+        let graph = FreqGraphResponse::from(exact.unwrap());
+        assert_eq!(graph.offset, 2);
+        assert_eq!(graph.values[0], 1f64 / 36f64);
+        assert_eq!(graph.max, 6f64 / 36f64);
+        assert_eq!(graph.truncated, 0f64);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_series() {
+        let chances = |series| response("d4", series, &[]).result.values;
+        assert_eq!(chances(Series::Exactly), vec![0.25; 4]);
+        assert_eq!(chances(Series::AtMost), vec![0.25, 0.5, 0.75, 1f64]);
+        assert_eq!(chances(Series::AtLeast), vec![1f64, 0.75, 0.5, 0.25]);
+        // Statistics are of the distribution, whatever the series
+        let stats = response("d4", Series::AtLeast, &[]).statistics.unwrap();
+        assert_eq!((stats.mean, stats.min, stats.max), (2.5, 1, 4));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_percentiles() {
+        let percentiles = [0f64, 25f64, 50f64, 100f64];
+        let stats = response("2d6", Series::AtMost, &percentiles)
+            .statistics
+            .unwrap();
+        let values: Vec<_> = stats
+            .percentiles
+            .iter()
+            .map(|p| (p.percent, p.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![(0f64, 2), (25f64, 5), (50f64, 7), (100f64, 12)]
+        );
+        assert_eq!(stats.median, 7);
+    }
 }
@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::{AnalyzeOptions, EvalError, Expr, Freq, FreqGraph};
+
+/// Distributions computed during a single analysis. Equal sub-expressions of a hand, such as
+/// every `d6` in `d6 + 2d6 + (d6)d6`, are analyzed only once
+pub(super) struct Cache<'a, F> {
+    pub(super) options: &'a AnalyzeOptions,
+    graphs: HashMap<Expr, FreqGraph<F>>,
+    powers: HashMap<Expr, Powers<F>>,
+}
+
+impl<'a, F: Freq> Cache<'a, F> {
+    pub(super) fn new(options: &'a AnalyzeOptions) -> Self {
+        Cache {
+            options,
+            graphs: HashMap::new(),
+            powers: HashMap::new(),
+        }
+    }
+
+    pub(super) fn get(&self, expr: &Expr) -> Option<&FreqGraph<F>> {
+        self.graphs.get(expr)
+    }
+
+    pub(super) fn insert(&mut self, expr: &Expr, graph: &FreqGraph<F>) {
+        self.graphs.insert(expr.clone(), graph.clone());
+    }
+
+    /// Power table of `expr`, the sums of any number of its outcomes
+    pub(super) fn powers(&mut self, expr: &Expr) -> Result<&mut Powers<F>, EvalError> {
+        if !self.powers.contains_key(expr) {
+            let base = expr.analyze(self)?;
+            self.powers.insert(expr.clone(), Powers::new(base));
+        }
+        Ok(self.powers.get_mut(expr).unwrap())
+    }
+}
+
+/// Sums of `k` independent outcomes of the same distribution, as in `kd6`. Every sum is
+/// computed once, starting from the largest smaller sum already known
+pub(super) struct Powers<F> {
+    base: FreqGraph<F>,
+    /// `base` summed `2^i` times
+    squares: Vec<FreqGraph<F>>,
+    sums: BTreeMap<u32, FreqGraph<F>>,
+}
+
+impl<F: Freq> Powers<F> {
+    fn new(base: FreqGraph<F>) -> Self {
+        let mut sums = BTreeMap::new();
+        sums.insert(0, FreqGraph::val(0));
+        Powers {
+            squares: vec![base.clone()],
+            base,
+            sums,
+        }
+    }
+
+    /// Distribution of the sum of `k` outcomes
+    pub(super) fn sum(&mut self, k: u32) -> &FreqGraph<F> {
+        if !self.sums.contains_key(&k) {
+            let (known, sum) = self.sums.range(..k).next_back().unwrap();
+            let (mut rest, mut sum) = (k - known, sum.clone());
+            let mut i = 0;
+            while rest > 0 {
+                if rest & 1 == 1 {
+                    sum = sum + self.square(i).clone();
+                }
+                rest >>= 1;
+                i += 1;
+            }
+            self.sums.insert(k, sum);
+        }
+        &self.sums[&k]
+    }
+
+    /// `base` summed `2^i` times
+    fn square(&mut self, i: usize) -> &FreqGraph<F> {
+        while self.squares.len() <= i {
+            let last = self.squares.last().unwrap();
+            self.squares.push(last.clone() + last.clone());
+        }
+        &self.squares[i]
+    }
+
+    /// Distribution of the sum of `count` independent outcomes. Negative count is the same as
+    /// no outcomes at all.
+    ///
+    /// Sums of less outcomes have less combinations, so every one of them is scaled by the total
+    /// of a single outcome for each missing outcome.
+    pub(super) fn repeated(&mut self, count: &FreqGraph<F>) -> FreqGraph<F> {
+        let total = self.base.total();
+        let max = i64::max(count.offset + count.values.len() as i64 - 1, 0);
+
+        let mut res: Option<FreqGraph<F>> = None;
+        for (n, freq) in count.values.iter().enumerate() {
+            let n = i64::max(n as i64 + count.offset, 0);
+            let term = self
+                .sum(n as u32)
+                .clone()
+                .times(freq.clone() * &total.pow((max - n) as u32));
+            res = Some(match res {
+                Some(res) => res.merge(term),
+                None => term,
+            });
+        }
+        let mut res = res.unwrap_or_else(|| FreqGraph::val(0).times(F::zero()));
+        res.truncated += count.truncated.clone() * &total.pow(max as u32);
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use wasm_bindgen_test::*;
+
+    use super::super::Hand;
+    use super::*;
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn powers_sum() {
+        let d6: FreqGraph = FreqGraph::uniform(6);
+        let mut powers = Powers::new(d6.clone());
+        let mut expected = FreqGraph::val(0);
+        let mut sums = Vec::new();
+        for _ in 0..=12 {
+            sums.push(expected.clone());
+            expected = expected + d6.clone();
+        }
+        // Out of order, so some sums start from the ones already known
+        for k in [7, 3, 12, 0, 1, 8].iter() {
+            assert_eq!(powers.sum(*k).offset, sums[*k as usize].offset);
+            assert_eq!(powers.sum(*k).values, sums[*k as usize].values);
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn shared_subexpressions() {
+        let options = AnalyzeOptions::default();
+        let mut cache: Cache<f64> = Cache::new(&options);
+        let Hand { expr, .. } = Hand::from_str("d6 + (d6)d6").unwrap();
+        let graph = expr.analyze(&mut cache).unwrap();
+        let Hand { expr: d6, .. } = Hand::from_str("d6").unwrap();
+        assert_eq!(cache.get(&d6).unwrap().values, vec![1f64; 6]);
+        assert_eq!(cache.get(&expr).unwrap().values, graph.values);
+        assert!(cache.powers.contains_key(&d6));
+
+        // Analysis doesn't consume the hand
+        let hand = Hand::from_str("2d6 + d6").unwrap();
+        assert_eq!(
+            hand.analyze().unwrap().values,
+            hand.analyze().unwrap().values
+        );
+    }
+}
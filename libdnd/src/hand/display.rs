@@ -0,0 +1,197 @@
+use std::fmt::{self, Display, Formatter};
+
+use super::{Cmp, Cond, Die, Expr, Hand, Op, Val};
+
+/// Canonical form of the expression, that parses back into the same hand. Implicit
+/// multiplications are spelled out, except for the number of dice, `2d6`
+impl Display for Hand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.expr.fmt(f)
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Expr::Value(Val::Num(n)) => write!(f, "{}", n),
+            Expr::Value(Val::Die(d)) => d.fmt(f),
+            Expr::Expr {
+                op: Op::Mul,
+                left,
+                right,
+            } if matches!(**left, Expr::Value(Val::Num(_))) && is_single_die(right) => {
+                write!(f, "{}{}", left, right)
+            }
+            Expr::Expr { op, left, right } => {
+                // All operators are left-associative, so the right side needs parens for the
+                // same priority too
+                write_operand(f, left, self.prio() > left.prio())?;
+                write!(f, " {} ", op.symbol())?;
+                write_operand(f, right, self.prio() >= right.prio())
+            }
+            Expr::Call { func, arg } => write!(f, "{}({})", func.name(), arg),
+            // Conditional expression is right-associative
+            Expr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                write_operand(f, cond, cond.prio() == 0)?;
+                write!(f, " ? {} : {}", then, otherwise)
+            }
+            Expr::Attack {
+                roll,
+                natural,
+                ac,
+                damage,
+                crit,
+            } => {
+                write!(f, "attack({} vs {}, {}", roll, ac, damage)?;
+                if *crit != natural.edges {
+                    write!(f, ", {}", crit)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Expr {
+    /// Priority of the outermost operator, values bind the strongest
+    fn prio(&self) -> u8 {
+        match self {
+            Expr::If { .. } => 0,
+            Expr::Expr { op: Op::Cmp(_), .. } => 1,
+            Expr::Expr {
+                op: Op::Add | Op::Sub,
+                ..
+            } => 2,
+            // Number of dice is part of the roll
+            Expr::Expr {
+                op: Op::Mul,
+                left,
+                right,
+            } if matches!(**left, Expr::Value(Val::Num(_))) && is_single_die(right) => 4,
+            Expr::Expr { .. } => 3,
+            Expr::Value(_) | Expr::Call { .. } | Expr::Attack { .. } => 4,
+        }
+    }
+}
+
+fn write_operand(f: &mut Formatter, expr: &Expr, parens: bool) -> fmt::Result {
+    if parens {
+        write!(f, "({})", expr)
+    } else {
+        expr.fmt(f)
+    }
+}
+
+/// Die that can follow its count right away, unlike `4d6kh3` that already has one
+fn is_single_die(expr: &Expr) -> bool {
+    matches!(expr, Expr::Value(Val::Die(d)) if d.rolls == 1)
+}
+
+/// Suffixes come in the order the parser reads them: reroll, explosion, keep and success
+impl Display for Die {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.rolls > 1 {
+            write!(f, "{}", self.rolls)?;
+        }
+        write!(f, "d{}", self.edges)?;
+        if let Some(reroll) = self.reroll {
+            let r = if reroll.once { "r" } else { "rr" };
+            write!(f, "{}{}", r, reroll.cond)?;
+        }
+        if let Some(explode) = self.explode {
+            write!(f, "{}", if explode.compound { "!!" } else { "!" })?;
+            if explode.threshold != self.edges {
+                write!(f, ">={}", explode.threshold)?;
+            }
+        }
+        // Every die is kept when none is dropped, but the suffix still carries the count
+        if self.drop_highest > 0 {
+            write!(f, "kl{}", self.rolls - self.drop_highest)?;
+        } else if self.rolls > 1 {
+            write!(f, "kh{}", self.rolls - self.drop_lowest)?;
+        }
+        if let Some(success) = self.success {
+            write!(f, "s{}", success.success)?;
+            if let Some(failure) = success.failure {
+                write!(f, "f{}", failure)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bare number is an equality
+impl Display for Cond {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let cmp = match self.cmp {
+            Cmp::Eq => "",
+            cmp => Op::Cmp(cmp).symbol(),
+        };
+        write!(f, "{}{}", cmp, self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    fn normalized(expr: &str) -> String {
+        Hand::from_str(expr).unwrap().to_string()
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn display_implicit() {
+        assert_eq!(normalized("2(d6)"), "2d6");
+        assert_eq!(normalized("d6d8"), "d6 * d8");
+        assert_eq!(normalized("(d4)d6 + 2(3)"), "d4 * d6 + 2 * 3");
+        assert_eq!(normalized("-3"), "0 - 3");
+        assert_eq!(normalized("d20a + d20d"), "2d20kh1 + 2d20kl1");
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn display_parens() {
+        assert_eq!(normalized("((1 + 2)) + 3"), "1 + 2 + 3");
+        assert_eq!(normalized("1 + (2 + 3)"), "1 + (2 + 3)");
+        assert_eq!(normalized("(1 + 2) * 3"), "(1 + 2) * 3");
+        assert_eq!(normalized("1 + 2 * 3 >= 7"), "1 + 2 * 3 >= 7");
+        assert_eq!(normalized("10 / (2 /^ 3)"), "10 / (2 /^ 3)");
+        assert_eq!(normalized("(1 ? 2 : 3) ? 4 : 5"), "(1 ? 2 : 3) ? 4 : 5");
+        assert_eq!(normalized("1 ? 2 : (3 ? 4 : 5)"), "1 ? 2 : 3 ? 4 : 5");
+        assert_eq!(normalized("(1 ? 2 : 3) + 1"), "(1 ? 2 : 3) + 1");
+        assert_eq!(normalized("adv(d20 + 2)"), "adv(d20 + 2)");
+        assert_eq!(normalized("20 / 2d6"), "20 / 2d6");
+        assert_eq!(normalized("20 / (2 * d6)"), "20 / 2d6");
+        assert_eq!(normalized("(20 / 2)d6"), "20 / 2 * d6");
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn display_die() {
+        assert_eq!(normalized("4d6kh3"), "4d6kh3");
+        assert_eq!(normalized("4d6dl1"), "4d6kh3");
+        assert_eq!(normalized("4d6dh0"), "4d6kh4");
+        assert_eq!(normalized("d6r<3"), "d6r<3");
+        assert_eq!(normalized("d20rr=1"), "d20rr1");
+        assert_eq!(normalized("d6!!>4"), "d6!!>=5");
+        assert_eq!(normalized("d10!>8s>=8f1"), "d10!>=9s>=8f1");
+        assert_eq!(normalized("d10!>9s>=8"), "d10!s>=8");
+        assert_eq!(normalized("d6s=6"), "d6s6");
+        assert_eq!(
+            normalized("attack(d20 + 5 vs 15, 2d6, 19)"),
+            "attack(d20 + 5 vs 15, 2d6, 19)"
+        );
+        assert_eq!(
+            normalized("attack(d20 vs 15, 1, 20)"),
+            "attack(d20 vs 15, 1)"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::f64::consts::PI;
+
+/// Convolution of two real sequences through the fast Fourier transform.
+///
+/// Result is only as precise as f64 allows for the largest of the values, so the smallest ones
+/// may come out slightly negative, which are clamped to zero.
+pub(super) fn convolve(left: &[f64], right: &[f64]) -> Vec<f64> {
+    let len = left.len() + right.len() - 1;
+    let size = len.next_power_of_two();
+
+    // Both sequences are transformed at once, as the real and imaginary parts of one. They are
+    // scaled to the same magnitude, or the smaller one would drown in the errors of the larger
+    let max = |values: &[f64]| values.iter().fold(0f64, |acc, v| f64::max(acc, *v));
+    let (lmax, rmax) = (max(left), max(right));
+    if lmax == 0f64 || rmax == 0f64 {
+        return vec![0f64; len];
+    }
+    let mut re = vec![0f64; size];
+    let mut im = vec![0f64; size];
+    for (n, v) in left.iter().enumerate() {
+        re[n] = v / lmax;
+    }
+    for (n, v) in right.iter().enumerate() {
+        im[n] = v / rmax;
+    }
+    fft(&mut re, &mut im, false);
+
+    // Transforms of real sequences are conjugate symmetric, which splits them apart again:
+    // L[k] = (Z[k] + conj(Z[-k])) / 2 and R[k] = (Z[k] - conj(Z[-k])) / 2i
+    let (mut pre, mut pim) = (vec![0f64; size], vec![0f64; size]);
+    for k in 0..size {
+        let j = (size - k) % size;
+        let (lre, lim) = ((re[k] + re[j]) / 2f64, (im[k] - im[j]) / 2f64);
+        let (rre, rim) = ((im[k] + im[j]) / 2f64, (re[j] - re[k]) / 2f64);
+        pre[k] = lre * rre - lim * rim;
+        pim[k] = lre * rim + lim * rre;
+    }
+    fft(&mut pre, &mut pim, true);
+
+    pre.truncate(len);
+    let scale = lmax * rmax / size as f64;
+    for v in pre.iter_mut() {
+        *v = f64::max(*v * scale, 0f64);
+    }
+    pre
+}
+
+/// Whether [`convolve`] of integer sequences rounds back to the exact result. The error of the
+/// transform is bounded by the precision of f64 times the norms of both sequences and the depth of
+/// the transform, which has to stay well below a half
+pub(super) fn exact(left: &[f64], right: &[f64]) -> bool {
+    let norm = |values: &[f64]| values.iter().map(|v| v * v).sum::<f64>().sqrt();
+    let depth = (left.len() + right.len())
+        .next_power_of_two()
+        .trailing_zeros() as f64
+        + 1f64;
+    norm(left) * norm(right) * depth < 2f64.powi(f64::MANTISSA_DIGITS as i32 - 8)
+}
+
+/// Iterative radix-2 transform in place, the length has to be a power of two. Inverse transform
+/// is left unscaled
+fn fft(re: &mut [f64], im: &mut [f64], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    // Twiddle factors of the largest pass, every smaller one takes every other of them
+    let sign = if inverse { 1f64 } else { -1f64 };
+    let angle = sign * 2f64 * PI / n as f64;
+    let twiddles: Vec<(f64, f64)> = (0..n / 2)
+        .map(|k| ((angle * k as f64).cos(), (angle * k as f64).sin()))
+        .collect();
+    let mut len = 2;
+    while len <= n {
+        let (half, step) = (len / 2, n / len);
+        for start in (0..n).step_by(len) {
+            for k in 0..half {
+                let (wre, wim) = twiddles[k * step];
+                let (a, b) = (start + k, start + k + half);
+                let (bre, bim) = (re[b] * wre - im[b] * wim, re[b] * wim + im[b] * wre);
+                re[b] = re[a] - bre;
+                im[b] = im[a] - bim;
+                re[a] += bre;
+                im[a] += bim;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn convolve_matches_naive() {
+        let left: Vec<f64> = (0..100).map(|v| (v * 7 % 13) as f64).collect();
+        let right: Vec<f64> = (0..37).map(|v| (v * 5 % 11) as f64).collect();
+        let mut expected = vec![0f64; left.len() + right.len() - 1];
+        for (ln, l) in left.iter().enumerate() {
+            for (rn, r) in right.iter().enumerate() {
+                expected[ln + rn] += l * r;
+            }
+        }
+        let actual = convolve(&left, &right);
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-9, "{} != {}", a, e);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use num_bigint::BigUint;
+use num_integer::Integer;
+use num_traits::{One, Pow, ToPrimitive, Zero};
+use std::fmt::Debug;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Sub};
+
+use super::fft;
+
+/// Length of the shorter sequence from which exact convolution switches from the naive algorithm
+/// to Kronecker substitution
+const FAST_CONVOLUTION: usize = 64;
+
+/// Same for FFT, which pays for several transforms of the whole length, see the `convolution`
+/// benchmark
+const FAST_FFT: usize = 512;
+
+/// Frequency of an outcome in a [`FreqGraph`](super::FreqGraph).
+///
+/// Frequencies count combinations of the dice, so they are never negative or fractional, and a
+/// division only ever happens by a divisor of the dividend. `f64` is fast but loses precision on
+/// large pools, [`BigUint`] is exact.
+pub trait Freq:
+    Clone
+    + Debug
+    + PartialEq
+    + PartialOrd
+    + Zero
+    + One
+    + Sum
+    + AddAssign
+    + for<'a> AddAssign<&'a Self>
+    + for<'a> Add<&'a Self, Output = Self>
+    + for<'a> Sub<&'a Self, Output = Self>
+    + for<'a> Mul<&'a Self, Output = Self>
+    + Div<Output = Self>
+{
+    fn pow(&self, n: u32) -> Self;
+
+    /// `self += a * b`, without cloning the operands
+    fn add_product(&mut self, a: &Self, b: &Self);
+
+    /// Chance of `self` out of `total`
+    fn ratio(&self, total: &Self) -> f64;
+
+    /// Least common multiple, the smallest total that both totals can be scaled to
+    fn lcm(&self, other: &Self) -> Self;
+
+    /// Whether the frequency still holds a number, floats run out of range on large hands
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    /// Frequencies of the sums of two independent outcomes, `result[i + j] = left[i] * right[j]`
+    fn convolve(left: &[Self], right: &[Self]) -> Vec<Self> {
+        let mut values = vec![Self::zero(); left.len() + right.len() - 1];
+        for (ln, lfreq) in left.iter().enumerate() {
+            for (rn, rfreq) in right.iter().enumerate() {
+                values[ln + rn].add_product(lfreq, rfreq);
+            }
+        }
+        values
+    }
+}
+
+impl Freq for f64 {
+    fn pow(&self, n: u32) -> Self {
+        self.powi(n as i32)
+    }
+
+    fn is_finite(&self) -> bool {
+        f64::is_finite(*self)
+    }
+
+    fn add_product(&mut self, a: &Self, b: &Self) {
+        *self += a * b
+    }
+
+    fn ratio(&self, total: &Self) -> f64 {
+        self / total
+    }
+
+    /// Integers past the mantissa aren't exact to begin with, so they are scaled to the larger
+    /// one instead
+    fn lcm(&self, other: &Self) -> Self {
+        let exact = (1u64 << f64::MANTISSA_DIGITS) as f64;
+        if f64::max(*self, *other) > exact {
+            return f64::max(*self, *other);
+        }
+        let (mut gcd, mut rem) = (*self, *other);
+        while rem != 0f64 {
+            let next = gcd % rem;
+            gcd = rem;
+            rem = next;
+        }
+        self / gcd * other
+    }
+
+    /// Large sequences are convolved with FFT as long as it's exact. Its errors grow with the
+    /// largest frequencies rather than with each one, so it's only used while they stay below a
+    /// half, see [`fft::exact`], and the result is rounded back to integers. Larger frequencies
+    /// are convolved naively, which keeps the small ones in the tails as precise as f64 allows
+    fn convolve(left: &[Self], right: &[Self]) -> Vec<Self> {
+        if usize::min(left.len(), right.len()) < FAST_FFT || !fft::exact(left, right) {
+            let mut values = vec![0f64; left.len() + right.len() - 1];
+            for (ln, lfreq) in left.iter().enumerate() {
+                for (rn, rfreq) in right.iter().enumerate() {
+                    values[ln + rn] += lfreq * rfreq;
+                }
+            }
+            return values;
+        }
+        let mut values = fft::convolve(left, right);
+        for v in values.iter_mut() {
+            *v = v.round();
+        }
+        values
+    }
+}
+
+impl Freq for BigUint {
+    fn pow(&self, n: u32) -> Self {
+        Pow::pow(self, n)
+    }
+
+    fn add_product(&mut self, a: &Self, b: &Self) {
+        *self += a * b
+    }
+
+    fn ratio(&self, total: &Self) -> f64 {
+        // Both are shifted down by the same amount to fit into f64, which keeps the ratio
+        let shift = total
+            .bits()
+            .saturating_sub(f64::MANTISSA_DIGITS as u64 * 16);
+        let f = |n: &BigUint| (n >> shift).to_f64().unwrap_or(f64::INFINITY);
+        f(self) / f(total)
+    }
+
+    fn lcm(&self, other: &Self) -> Self {
+        Integer::lcm(self, other)
+    }
+
+    /// Large sequences are convolved with Kronecker substitution: both are packed into a single
+    /// big integer with every frequency in its own wide enough slot, and the product of the two
+    /// integers holds the frequencies of the result in the same slots
+    fn convolve(left: &[Self], right: &[Self]) -> Vec<Self> {
+        let shorter = usize::min(left.len(), right.len());
+        if shorter < FAST_CONVOLUTION {
+            let mut values = vec![BigUint::zero(); left.len() + right.len() - 1];
+            for (ln, lfreq) in left.iter().enumerate() {
+                for (rn, rfreq) in right.iter().enumerate() {
+                    values[ln + rn] += lfreq * rfreq;
+                }
+            }
+            return values;
+        }
+
+        // Every frequency of the result is a sum of at most `shorter` products
+        let bits = |values: &[BigUint]| values.iter().map(BigUint::bits).max().unwrap_or(0);
+        let slot_bits = bits(left)
+            + bits(right)
+            + (shorter as u64).next_power_of_two().trailing_zeros() as u64
+            + 1;
+        let slot = slot_bits.div_ceil(32) as usize;
+        let pack = |values: &[BigUint]| {
+            let mut digits = vec![0u32; values.len() * slot];
+            for (n, freq) in values.iter().enumerate() {
+                for (i, digit) in freq.to_u32_digits().into_iter().enumerate() {
+                    digits[n * slot + i] = digit;
+                }
+            }
+            BigUint::new(digits)
+        };
+
+        let digits = (pack(left) * pack(right)).to_u32_digits();
+        (0..left.len() + right.len() - 1)
+            .map(|n| {
+                let from = usize::min(n * slot, digits.len());
+                let to = usize::min(from + slot, digits.len());
+                BigUint::from_slice(&digits[from..to])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn convolve_large() {
+        let left: Vec<u32> = (0..1500).map(|v| v * 7919 % 1009).collect();
+        let right: Vec<u32> = (0..600).map(|v| v * 104_729 % 997).collect();
+        let exact = |values: &[u32]| values.iter().map(|v| BigUint::from(*v)).collect::<Vec<_>>();
+        let float = |values: &[u32]| values.iter().map(|v| *v as f64).collect::<Vec<_>>();
+        let mut expected = vec![0u64; left.len() + right.len() - 1];
+        for (ln, l) in left.iter().enumerate() {
+            for (rn, r) in right.iter().enumerate() {
+                expected[ln + rn] += *l as u64 * *r as u64;
+            }
+        }
+
+        let actual = BigUint::convolve(&exact(&left), &exact(&right));
+        assert_eq!(
+            actual,
+            expected
+                .iter()
+                .map(|v| BigUint::from(*v))
+                .collect::<Vec<_>>()
+        );
+        let actual = f64::convolve(&float(&left), &float(&right));
+        assert_eq!(
+            actual,
+            expected.iter().map(|v| *v as f64).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn ratio_beyond_f64() {
+        let total = Freq::pow(&BigUint::from(6u32), 1000);
+        assert_eq!(total.ratio(&total), 1f64);
+        assert_eq!((total.clone() / BigUint::from(4u32)).ratio(&total), 0.25);
+        assert_eq!(BigUint::from(1u32).ratio(&total), 0f64);
+        assert_eq!(3f64.ratio(&4f64), 0.75);
+    }
+}
@@ -0,0 +1,275 @@
+use serde_derive::Serialize;
+use std::convert::TryFrom;
+
+use super::{Die, EvalError, Expr, Op, Val, MAX_VALUE};
+
+/// Upper bounds on the work a single hand can take, so no expression can bring down the worker.
+/// The parser checks the dice as they are written, the throw and the analysis check everything
+/// that is only known once the hand is evaluated
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Limits {
+    /// Dice rolled at once, by a die such as `4d6` or by a count such as `(d4)d6`
+    pub max_dice: u32,
+    /// Faces of a single die
+    pub max_faces: u32,
+    /// Outcomes of any distribution of the analysis, from the lowest to the highest one
+    pub max_support: u64,
+    /// Dice rolled and operations of a throw, or outcomes combined by an analysis
+    pub max_operations: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_dice: 1_000,
+            max_faces: 10_000,
+            max_support: 100_000,
+            max_operations: 100_000_000,
+        }
+    }
+}
+
+/// Limit that a hand goes past
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    Dice,
+    Faces,
+    Support,
+    Operations,
+}
+
+impl Limit {
+    pub(super) fn exceeded(self, value: u64, max: u64) -> Result<(), EvalError> {
+        if value > max {
+            Err(EvalError::LimitExceeded {
+                limit: self,
+                value,
+                max,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Operations of a single throw: every sub-expression and every die rolled
+pub(super) struct Budget<'a> {
+    limits: &'a Limits,
+    operations: u64,
+}
+
+impl<'a> Budget<'a> {
+    pub(super) fn new(limits: &'a Limits) -> Self {
+        Budget {
+            limits,
+            operations: 0,
+        }
+    }
+
+    pub(super) fn spend(&mut self, operations: u64) -> Result<(), EvalError> {
+        self.operations = self.operations.saturating_add(operations);
+        Limit::Operations.exceeded(self.operations, self.limits.max_operations)
+    }
+
+    /// Checks `count` dice before they are rolled one by one, each of them spends its own roll
+    pub(super) fn dice(&self, count: i64) -> Result<(), EvalError> {
+        let count = u64::try_from(count).unwrap_or(0);
+        Limit::Dice.exceeded(count, self.limits.max_dice as u64)
+    }
+}
+
+/// Outcomes of an expression lie within `min..=max`
+#[derive(Debug, Copy, Clone)]
+struct Range {
+    min: i128,
+    max: i128,
+}
+
+impl Range {
+    fn val(n: i128) -> Self {
+        Range { min: n, max: n }
+    }
+
+    /// Number of outcomes from the lowest to the highest, the length of the analyzed graph
+    fn support(self) -> u64 {
+        saturate(self.max - self.min + 1)
+    }
+
+    fn union(self, other: Self) -> Self {
+        Range {
+            min: i128::min(self.min, other.min),
+            max: i128::max(self.max, other.max),
+        }
+    }
+
+    fn times(self, other: Self) -> Self {
+        let corners = [
+            self.min * other.min,
+            self.min * other.max,
+            self.max * other.min,
+            self.max * other.max,
+        ];
+        Range {
+            min: *corners.iter().min().unwrap(),
+            max: *corners.iter().max().unwrap(),
+        }
+    }
+}
+
+fn saturate(n: i128) -> u64 {
+    u64::try_from(n).unwrap_or(u64::MAX)
+}
+
+/// Checks a hand before it is analyzed, since the distributions are allocated all at once. Every
+/// sub-expression is given the range of its outcomes and a rough count of the operations that
+/// it takes to analyze
+pub(super) struct Check<'a> {
+    limits: &'a Limits,
+    depth: u32,
+    operations: u64,
+}
+
+impl<'a> Check<'a> {
+    pub(super) fn new(limits: &'a Limits, explosion_depth: u32) -> Self {
+        Check {
+            limits,
+            depth: explosion_depth,
+            operations: 0,
+        }
+    }
+
+    /// Fails if any sub-expression of `expr` can go past the limits, or past [`MAX_VALUE`]
+    pub(super) fn run(&mut self, expr: &Expr) -> Result<(), EvalError> {
+        self.range(expr).map(|_| ())
+    }
+
+    fn range(&mut self, expr: &Expr) -> Result<Range, EvalError> {
+        let (range, operations) = match expr {
+            Expr::Value(Val::Num(n)) => (Range::val(*n as i128), 1),
+            Expr::Value(Val::Die(d)) => self.die(d),
+            // Sums of every count of the dice
+            Expr::Expr {
+                op: Op::Mul,
+                left,
+                right,
+            } if right.is_die() => {
+                let count = self.range(left)?;
+                Limit::Dice.exceeded(saturate(count.max), self.limits.max_dice as u64)?;
+                let die = self.range(right)?;
+                let range = count.union(Range::val(0)).times(die);
+                let sums = count.support().saturating_mul(range.support());
+                (range, sums.saturating_mul(die.support()))
+            }
+            Expr::Expr { op, left, right } => {
+                let (l, r) = (self.range(left)?, self.range(right)?);
+                let range = match op {
+                    Op::Add => Range {
+                        min: l.min + r.min,
+                        max: l.max + r.max,
+                    },
+                    Op::Sub => Range {
+                        min: l.min - r.max,
+                        max: l.max - r.min,
+                    },
+                    Op::Mul => l.times(r),
+                    // Divisor is never zero, so the quotient is no larger than the dividend
+                    Op::Div(_) => {
+                        let max = i128::max(l.min.abs(), l.max.abs());
+                        Range { min: -max, max }
+                    }
+                    Op::Cmp(_) => Range { min: 0, max: 1 },
+                };
+                (range, l.support().saturating_mul(r.support()))
+            }
+            Expr::Call { arg, .. } => {
+                let arg = self.range(arg)?;
+                (arg, arg.support())
+            }
+            Expr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.range(cond)?;
+                let range = self.range(then)?.union(self.range(otherwise)?);
+                (range, range.support())
+            }
+            // Rest of the roll is analyzed again for every natural roll
+            Expr::Attack {
+                roll,
+                natural,
+                ac,
+                damage,
+                ..
+            } => {
+                let (natural, analyzed) = self.die(natural);
+                let before = self.operations;
+                let roll = self.range(roll)?;
+                let rolls = (self.operations - before).saturating_mul(natural.support());
+                let ac = self.range(ac)?;
+                let compared = natural.support().saturating_mul(roll.support());
+                let compared = compared.saturating_mul(ac.support());
+                let doubled = damage.as_ref().clone().doubled_dice();
+                let range = Range::val(0)
+                    .union(self.range(damage)?)
+                    .union(self.range(&doubled)?);
+                (
+                    range,
+                    analyzed.saturating_add(rolls).saturating_add(compared),
+                )
+            }
+        };
+        if range.min < -(MAX_VALUE as i128) || range.max > MAX_VALUE as i128 {
+            return Err(EvalError::Overflow);
+        }
+        Limit::Support.exceeded(range.support(), self.limits.max_support)?;
+        self.operations = self.operations.saturating_add(operations);
+        Limit::Operations.exceeded(self.operations, self.limits.max_operations)?;
+        Ok(range)
+    }
+
+    /// Every explosion adds at most another face, or another success, and the kept dice are
+    /// walked face by face for every number of dice
+    fn die(&self, d: &Die) -> (Range, u64) {
+        let rolls = match d.explode {
+            Some(_) => self.depth as i128 + 1,
+            None => 1,
+        };
+        let single = match d.success {
+            Some(_) => Range {
+                min: -rolls,
+                max: rolls,
+            },
+            None => Range {
+                min: 1,
+                max: d.edges as i128 * rolls,
+            },
+        };
+        let kept = d.rolls.saturating_sub(d.drop_lowest + d.drop_highest) as i128;
+        let range = Range {
+            min: single.min * kept,
+            max: single.max * kept,
+        };
+        let exploded = match d.explode {
+            Some(_) => single
+                .support()
+                .saturating_mul(rolls as u64 * d.edges as u64),
+            None => single.support(),
+        };
+        let faces = single.support();
+        let kept = match d.rolls.saturating_sub(d.drop_lowest + d.drop_highest) {
+            _ if d.rolls == 1 => 0,
+            // Single highest or lowest die takes a power of every face
+            1 if d.drop_lowest == 0 || d.drop_highest == 0 => faces.saturating_mul(64),
+            // Every face, for every number of dice assigned so far, every sum and every count
+            // of the face
+            _ => {
+                let n = d.rolls as u64;
+                let counts = (n + 1) * (n + 2) / 2;
+                faces.saturating_mul(range.support()).saturating_mul(counts)
+            }
+        };
+        (range, exploded.saturating_add(kept))
+    }
+}
@@ -1,32 +1,148 @@
+use rand::Rng;
+use serde_derive::Serialize;
+use std::convert::TryFrom;
 use std::str::FromStr;
 
+mod cache;
+mod display;
+mod fft;
+mod freq;
+mod limits;
 mod parser;
+mod stats;
+mod trace;
 
+use cache::Cache;
+pub use freq::Freq;
+use limits::{Budget, Check};
+pub use limits::{Limit, Limits};
 pub use parser::ParseError;
-use parser::Tokens;
+pub use stats::Statistics;
+pub use trace::{DieRoll, Outcome, Trace};
 
-pub struct Hand(Expr);
+/// Upper bound on explosions of a single die in [`Hand::throw`], so `d2!` can't roll forever
+const MAX_EXPLOSIONS: u32 = 100;
+
+/// Parsed dice expression, that can be thrown and analyzed any number of times
+#[derive(Debug, Clone)]
+pub struct Hand {
+    expr: Expr,
+    /// Limits the hand was parsed with, that its throws and analyses stay within
+    limits: Limits,
+}
 
 impl Hand {
-    pub fn throw(self) -> i64 {
-        self.0.throw()
+    /// Parses `expr` that has to stay within the `limits`, while [`Hand::from_str`] uses the
+    /// default ones
+    pub fn parse_with(expr: &str, limits: &Limits) -> Result<Self, Vec<ParseError>> {
+        parser::parse(expr, limits).map(|expr| Hand {
+            expr,
+            limits: *limits,
+        })
+    }
+
+    pub fn throw(&self) -> Result<i64, EvalError> {
+        self.throw_with(&mut rand::thread_rng())
+    }
+
+    /// Throws the dice with `rng`, so a seeded generator throws the same results every time
+    pub fn throw_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<i64, EvalError> {
+        self.trace_with(rng).map(|trace| trace.value())
+    }
+
+    /// Throws the dice with `rng`, keeping every die rolled and the value of every
+    /// sub-expression
+    pub fn trace_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<Trace, EvalError> {
+        self.expr.throw(rng, &mut Budget::new(&self.limits))
+    }
+
+    /// Results of `n` independent throws
+    pub fn throw_n(&self, n: usize) -> Result<Vec<i64>, EvalError> {
+        let mut rng = rand::thread_rng();
+        (0..n).map(|_| self.throw_with(&mut rng)).collect()
     }
 
-    pub fn analyze(self) -> FreqGraph {
-        self.0.analyze()
+    pub fn analyze(&self) -> Result<FreqGraph, EvalError> {
+        self.analyze_with(&AnalyzeOptions::default())
+    }
+
+    /// Analyzes the hand with frequencies of type `F`, e.g. exact [`num_bigint::BigUint`] counts
+    pub fn analyze_with<F: Freq>(
+        &self,
+        options: &AnalyzeOptions,
+    ) -> Result<FreqGraph<F>, EvalError> {
+        // Every outcome is checked up front, so the analysis itself can't overflow or run away
+        Check::new(&self.limits, options.explosion_depth).run(&self.expr)?;
+        let graph: FreqGraph<F> = self.expr.analyze(&mut Cache::new(options))?;
+        if graph.total().is_finite() {
+            Ok(graph)
+        } else {
+            Err(EvalError::Overflow)
+        }
     }
 }
 
+/// Largest magnitude of a value of any sub-expression, so it survives the trip through JavaScript
+/// numbers
+const MAX_VALUE: i64 = 1 << 53;
+
+/// Errors of an expression that was parsed successfully, but can't be evaluated
+#[derive(Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum EvalError {
+    DivisionByZero,
+    /// Value larger than [`MAX_VALUE`] in magnitude, or frequencies too large for the analysis
+    Overflow,
+    /// Hand goes past its [`Limits`], `value` is more than the `max` allowed
+    LimitExceeded {
+        limit: Limit,
+        value: u64,
+        max: u64,
+    },
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Error {
+    Parse(ParseError),
+    Eval(EvalError),
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Self {
+        Error::Eval(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct AnalyzeOptions {
+    /// Number of explosions followed for every exploding die. Anything past that is cut off and
+    /// reported as [`FreqGraph::truncated`]
+    pub explosion_depth: u32,
+}
+
+impl Default for AnalyzeOptions {
+    fn default() -> Self {
+        AnalyzeOptions { explosion_depth: 5 }
+    }
+}
+
+/// Fails with every error of the expression, in the order they appear in it
 impl FromStr for Hand {
-    type Err = ParseError;
+    type Err = Vec<ParseError>;
 
     fn from_str(expr: &str) -> Result<Self, Self::Err> {
-        let expr = Tokens::from_str(expr)?.normalize()?.to_expr();
-        Ok(Hand(expr))
+        Hand::parse_with(expr, &Limits::default())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 enum Expr {
     Value(Val),
     Expr {
@@ -34,63 +150,326 @@ enum Expr {
         left: Box<Expr>,
         right: Box<Expr>,
     },
+    Call {
+        func: Func,
+        arg: Box<Expr>,
+    },
+    If {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+    /// Damage of an attack that hits when the `roll` meets the armor class, `ac`. Natural 1 of
+    /// the roll always misses, and natural roll of at least `crit` is a critical hit that rolls
+    /// the damage dice twice
+    Attack {
+        roll: Box<Expr>,
+        /// Die that the roll starts with, which decides the natural roll
+        natural: Die,
+        ac: Box<Expr>,
+        damage: Box<Expr>,
+        crit: u32,
+    },
 }
 
 impl Expr {
-    fn throw(self) -> i64 {
-        match self {
-            Self::Value(Val::Num(n)) => n as i64,
-            Self::Value(Val::Die(d)) => (rand::random::<u32>() % d.edges + 1) as i64,
+    fn throw<R: Rng + ?Sized>(&self, rng: &mut R, budget: &mut Budget) -> Result<Trace, EvalError> {
+        budget.spend(1)?;
+        let res = match self {
+            Self::Value(Val::Num(n)) => Trace::Num { value: *n as i64 },
+            Self::Value(Val::Die(d)) => {
+                budget.spend(d.rolls as u64)?;
+                d.throw(rng)
+            }
             Self::Expr {
                 op: Op::Mul,
                 left,
                 right,
             } if right.is_die() => {
-                let left = left.throw();
-                (0..left).fold(0, |acc, _| acc + right.clone().throw())
+                let count = left.throw(rng, budget)?;
+                budget.dice(count.value())?;
+                let dice = (0..count.value())
+                    .map(|_| right.throw(rng, budget))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let value = dice
+                    .iter()
+                    .try_fold(0i64, |sum, d| sum.checked_add(d.value()));
+                Trace::Repeated {
+                    value: in_range(value)?,
+                    count: Box::new(count),
+                    dice,
+                }
             }
             Self::Expr { op, left, right } => {
-                let left = left.throw();
-                let right = right.throw();
-                match op {
-                    Op::Add => left + right,
-                    Op::Sub => left - right,
-                    Op::Mul => left * right,
+                let left = left.throw(rng, budget)?;
+                let right = right.throw(rng, budget)?;
+                let (l, r) = (left.value(), right.value());
+                let value = match *op {
+                    Op::Add => l.checked_add(r),
+                    Op::Sub => l.checked_sub(r),
+                    Op::Mul => l.checked_mul(r),
+                    Op::Div(_) if r == 0 => Err(EvalError::DivisionByZero)?,
+                    Op::Div(rounding) => Some(rounding.div(l, r)),
+                    Op::Cmp(cmp) => Some(cmp.test(l, r) as i64),
+                };
+                let value = in_range(value)?;
+                Trace::Op {
+                    value,
+                    op: op.symbol(),
+                    left: Box::new(left),
+                    right: Box::new(right),
                 }
             }
-        }
+            Self::Call { func, arg } => {
+                let first = arg.throw(rng, budget)?;
+                let second = arg.throw(rng, budget)?;
+                let value = match func {
+                    Func::Adv => i64::max(first.value(), second.value()),
+                    Func::Dis => i64::min(first.value(), second.value()),
+                };
+                Trace::Call {
+                    value,
+                    func: func.name(),
+                    first: Box::new(first),
+                    second: Box::new(second),
+                }
+            }
+            // Only the branch that is taken is thrown
+            Self::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let cond = cond.throw(rng, budget)?;
+                let branch = if cond.value() != 0 {
+                    then.throw(rng, budget)?
+                } else {
+                    otherwise.throw(rng, budget)?
+                };
+                Trace::If {
+                    value: branch.value(),
+                    cond: Box::new(cond),
+                    branch: Box::new(branch),
+                }
+            }
+            Self::Attack {
+                roll,
+                natural,
+                ac,
+                damage,
+                crit,
+            } => {
+                let ac = ac.throw(rng, budget)?;
+                budget.spend(1)?;
+                let natural = natural.throw(rng);
+                // Faces of the natural die are never below 1, anything that is would miss
+                let (outcome, roll) = match u32::try_from(natural.value()) {
+                    Ok(n) if n >= *crit => (Outcome::Critical, None),
+                    Ok(n) if n > 1 => {
+                        let roll = roll.as_ref().clone().with_natural(n).throw(rng, budget)?;
+                        let outcome = if roll.value() >= ac.value() {
+                            Outcome::Hit
+                        } else {
+                            Outcome::Miss
+                        };
+                        (outcome, Some(Box::new(roll)))
+                    }
+                    _ => (Outcome::Miss, None),
+                };
+                let damage = match outcome {
+                    Outcome::Miss => None,
+                    Outcome::Hit => Some(damage.throw(rng, budget)?),
+                    Outcome::Critical => {
+                        Some(damage.as_ref().clone().doubled_dice().throw(rng, budget)?)
+                    }
+                };
+                Trace::Attack {
+                    value: damage.as_ref().map_or(0, Trace::value),
+                    natural: Box::new(natural),
+                    roll,
+                    ac: Box::new(ac),
+                    outcome,
+                    damage: damage.map(Box::new),
+                }
+            }
+        };
+        Ok(res)
     }
 
-    fn analyze(self) -> FreqGraph {
-        match self {
-            Self::Value(Val::Num(n)) => FreqGraph::val(n),
-            Self::Value(Val::Die(Die { edges, .. })) => FreqGraph::die(edges),
+    /// Analyzes every distinct sub-expression once, the rest come from the `cache`
+    fn analyze<F: Freq>(&self, cache: &mut Cache<F>) -> Result<FreqGraph<F>, EvalError> {
+        if let Some(graph) = cache.get(self) {
+            return Ok(graph.clone());
+        }
+        let res = match self {
+            Self::Value(Val::Num(n)) => FreqGraph::val(*n),
+            Self::Value(Val::Die(d)) => FreqGraph::die(*d, cache.options),
             Self::Expr {
                 op: Op::Mul,
                 left,
                 right,
             } if right.is_die() => {
-                let left = left.analyze();
-                let right = right.analyze();
-                left.values
-                    .iter()
-                    .enumerate()
-                    .map(|(v, f)| (v as i64 + left.offset, f))
-                    .fold(FreqGraph::val(0), |acc, (v, f)| {
-                        acc + (0..v)
-                            .fold(FreqGraph::val(0), |acc, _| acc + right.clone())
-                            .times(*f)
-                    })
+                let left = left.analyze(cache)?;
+                cache.powers(right)?.repeated(&left)
             }
             Self::Expr { op, left, right } => {
-                let left = left.analyze();
-                let right = right.analyze();
-                match op {
+                let left = left.analyze(cache)?;
+                let right = right.analyze(cache)?;
+                match *op {
                     Op::Add => left + right,
                     Op::Sub => left - right,
                     Op::Mul => left * right,
+                    Op::Div(rounding) => left.divide(right, rounding)?,
+                    Op::Cmp(cmp) => left.compare(right, cmp),
+                }
+            }
+            Self::Call { func, arg } => {
+                let arg = arg.analyze(cache)?;
+                match func {
+                    Func::Adv => arg.best_of_two(),
+                    Func::Dis => arg.worst_of_two(),
+                }
+            }
+            Self::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let cond: FreqGraph<F> = cond.analyze(cache)?;
+                let zero = cond.slice(0, 1).total();
+                let nonzero = cond.total() - &cond.truncated - &zero;
+                // Branch that can't be taken is never thrown, so it can't fail either
+                let then = if nonzero.is_zero() {
+                    None
+                } else {
+                    Some(then.analyze(cache)?)
+                };
+                let otherwise = if zero.is_zero() {
+                    None
+                } else {
+                    Some(otherwise.analyze(cache)?)
+                };
+                FreqGraph::branch(&cond, then, otherwise)
+            }
+            Self::Attack {
+                roll,
+                natural,
+                ac,
+                damage,
+                crit,
+            } => {
+                let natural: FreqGraph<F> = FreqGraph::die(*natural, cache.options);
+                let ac = ac.analyze(cache)?;
+                // Outcome of the attack for every natural roll: 0 is a miss, 1 is a hit and 2 is
+                // a critical hit
+                let mut outcomes = Vec::new();
+                for (n, freq) in natural.values.iter().enumerate() {
+                    let n = n as i64 + natural.offset;
+                    let outcome = match u32::try_from(n) {
+                        _ if freq.is_zero() => continue,
+                        Ok(n) if n >= *crit => FreqGraph::val(2),
+                        Ok(n) if n > 1 => roll
+                            .as_ref()
+                            .clone()
+                            .with_natural(n)
+                            .analyze(cache)?
+                            .compare(ac.clone(), Cmp::Ge),
+                        _ => FreqGraph::val(0),
+                    };
+                    outcomes.push((freq.clone(), outcome));
+                }
+                let outcome = FreqGraph::mixture(outcomes, natural.truncated.clone());
+
+                // Damage that has no chance to be dealt is never thrown
+                let mut damages = Vec::new();
+                let (miss, hit, critical) = (
+                    outcome.slice(0, 1).total(),
+                    outcome.slice(1, 2).total(),
+                    outcome.slice(2, 3).total(),
+                );
+                if !miss.is_zero() {
+                    damages.push((miss, FreqGraph::val(0)));
+                }
+                if !hit.is_zero() {
+                    damages.push((hit, damage.analyze(cache)?));
+                }
+                if !critical.is_zero() {
+                    let doubled = damage.as_ref().clone().doubled_dice();
+                    damages.push((critical, doubled.analyze(cache)?));
                 }
+                FreqGraph::mixture(damages, outcome.truncated)
             }
+        };
+        cache.insert(self, &res);
+        Ok(res)
+    }
+
+    /// Attack roll with the natural roll replaced by its outcome. The parser guarantees that
+    /// the roll starts with the natural die, so anything else is left as it is
+    fn with_natural(self, n: u32) -> Self {
+        match self {
+            Self::Value(Val::Die(_)) => Self::Value(Val::Num(n)),
+            Self::Expr { op, left, right } => Self::Expr {
+                op,
+                left: Box::new(left.with_natural(n)),
+                right,
+            },
+            Self::If {
+                cond,
+                then,
+                otherwise,
+            } => Self::If {
+                cond: Box::new(cond.with_natural(n)),
+                then,
+                otherwise,
+            },
+            Self::Value(Val::Num(_)) | Self::Call { .. } | Self::Attack { .. } => self,
+        }
+    }
+
+    /// Damage of a critical hit, that rolls twice as many dice. Numbers stay the same, as well as
+    /// the dice that decide how many dice to roll, and the conditions
+    fn doubled_dice(self) -> Self {
+        let twice = |e: Box<Expr>| Self::Expr {
+            op: Op::Mul,
+            left: Box::new(Self::Value(Val::Num(2))),
+            right: e,
+        };
+        match self {
+            Self::Value(Val::Die(_)) => twice(Box::new(self)),
+            Self::Expr {
+                op: Op::Mul,
+                left,
+                right,
+            } if right.is_die() => Self::Expr {
+                op: Op::Mul,
+                left: Box::new(Self::Expr {
+                    op: Op::Mul,
+                    left,
+                    right: Box::new(Self::Value(Val::Num(2))),
+                }),
+                right,
+            },
+            Self::Expr { op, left, right } => Self::Expr {
+                op,
+                left: Box::new(left.doubled_dice()),
+                right: Box::new(right.doubled_dice()),
+            },
+            Self::Call { func, arg } => Self::Call {
+                func,
+                arg: Box::new(arg.doubled_dice()),
+            },
+            Self::If {
+                cond,
+                then,
+                otherwise,
+            } => Self::If {
+                cond,
+                then: Box::new(then.doubled_dice()),
+                otherwise: Box::new(otherwise.doubled_dice()),
+            },
+            // Nested attack rolls its own crits
+            Self::Value(Val::Num(_)) | Self::Attack { .. } => self,
         }
     }
 
@@ -103,115 +482,657 @@ impl Expr {
     }
 }
 
+/// Value of a throw, if it didn't overflow or go past [`MAX_VALUE`]
+fn in_range(value: Option<i64>) -> Result<i64, EvalError> {
+    value
+        .filter(|v| v.abs() <= MAX_VALUE)
+        .ok_or(EvalError::Overflow)
+}
+
 #[derive(Debug, Clone)]
-pub struct FreqGraph {
+pub struct FreqGraph<F = f64> {
     pub offset: i64,
-    pub values: Vec<f64>,
+    pub values: Vec<F>,
+    /// Frequency of the outcomes that were cut off and are not present in `values`
+    pub truncated: F,
 }
 
-impl FreqGraph {
+impl<F: Freq> FreqGraph<F> {
     fn val(n: u32) -> Self {
         Self {
             offset: n as i64,
-            values: vec![1f64],
+            values: vec![F::one()],
+            truncated: F::zero(),
         }
     }
 
-    fn die(d: u32) -> Self {
-        let vec = vec![1f64; d as usize];
+    fn die(d: Die, options: &AnalyzeOptions) -> Self {
+        let mut single = Self::uniform(d.edges);
+        if let Some(reroll) = d.reroll {
+            single = single.rerolled(reroll);
+        }
+        let count = |v| d.success.map_or(v, |s| s.count(v));
+        single = match d.explode {
+            // Every exploded roll is counted on its own
+            Some(Explode {
+                compound: false,
+                threshold,
+            }) => single.exploded(threshold as i64, options.explosion_depth, count),
+            Some(Explode {
+                compound: true,
+                threshold,
+            }) => single
+                .exploded(threshold as i64, options.explosion_depth, |v| v)
+                .map(count),
+            None => single.map(count),
+        };
+        match d.rolls - d.drop_lowest - d.drop_highest {
+            _ if d.rolls == 1 => single,
+            // Only the highest or the lowest die is kept: `4d6kh1`, `3d20kl1`
+            1 if d.drop_highest == 0 => single.extreme(d.rolls, true),
+            1 if d.drop_lowest == 0 => single.extreme(d.rolls, false),
+            _ => single.kept(d.rolls, d.drop_lowest, d.drop_highest),
+        }
+    }
+
+    fn uniform(edges: u32) -> Self {
+        let vec = vec![F::one(); edges as usize];
         Self {
             offset: 1,
             values: vec,
+            truncated: F::zero(),
+        }
+    }
+
+    /// Sum of all frequencies, including the truncated ones
+    pub fn total(&self) -> F {
+        self.values.iter().cloned().sum::<F>() + &self.truncated
+    }
+
+    /// Frequencies of `self` and `other` taken together, as if the outcomes were collected from
+    /// both sources
+    fn merge(self, other: Self) -> Self {
+        let offset = i64::min(self.offset, other.offset);
+        let end = i64::max(
+            self.offset + self.values.len() as i64,
+            other.offset + other.values.len() as i64,
+        );
+        let mut values = vec![F::zero(); (end - offset) as usize];
+        for graph in [&self, &other].iter() {
+            let shift = (graph.offset - offset) as usize;
+            for (n, freq) in graph.values.iter().enumerate() {
+                values[shift + n] += freq;
+            }
+        }
+        Self {
+            offset,
+            values,
+            truncated: self.truncated + &other.truncated,
+        }
+    }
+
+    /// Distribution of `f(outcome)`
+    fn map(&self, f: impl Fn(i64) -> i64) -> Self {
+        let outcomes = || {
+            self.values
+                .iter()
+                .enumerate()
+                .filter(|(_, freq)| !freq.is_zero())
+                .map(|(n, freq)| (f(n as i64 + self.offset), freq))
+        };
+        let offset = outcomes().map(|(v, _)| v).min().unwrap_or(self.offset);
+        let end = outcomes()
+            .map(|(v, _)| v)
+            .max()
+            .map_or(offset, |max| max + 1);
+        let mut values = vec![F::zero(); (end - offset) as usize];
+        for (outcome, freq) in outcomes() {
+            values[(outcome - offset) as usize] += freq;
+        }
+        Self {
+            offset,
+            values,
+            truncated: self.truncated.clone(),
         }
     }
 
-    fn times(self, t: f64) -> Self {
+    /// Outcomes of `self` within `[from, to)`
+    fn slice(&self, from: i64, to: i64) -> Self {
+        let from = i64::max(from, self.offset);
+        let to = i64::min(to, self.offset + self.values.len() as i64);
+        let values = if from < to {
+            self.values[(from - self.offset) as usize..(to - self.offset) as usize].to_vec()
+        } else {
+            Vec::new()
+        };
+        Self {
+            offset: from,
+            values,
+            truncated: F::zero(),
+        }
+    }
+
+    /// Distribution of a die that is rolled again when the outcome matches the `reroll` condition.
+    ///
+    /// A single reroll has `total` times more combinations, and rerolled outcomes are spread
+    /// evenly over the whole distribution. Rerolling until the condition doesn't match is the
+    /// same as excluding the matching outcomes.
+    fn rerolled(&self, reroll: Reroll) -> Self {
+        let total = self.total();
+        let rerolled: F = self
+            .values
+            .iter()
+            .enumerate()
+            .filter(|(n, _)| reroll.cond.matches(*n as i64 + self.offset))
+            .map(|(_, f)| f.clone())
+            .sum();
+        let kept = total + &rerolled;
+        let values = self.values.iter().enumerate().map(|(n, f)| {
+            let matches = reroll.cond.matches(n as i64 + self.offset);
+            match (reroll.once, matches) {
+                (true, true) => f.clone() * &rerolled,
+                (true, false) => f.clone() * &kept,
+                (false, true) => F::zero(),
+                (false, false) => f.clone(),
+            }
+        });
         Self {
-            values: self.values.into_iter().map(|f| f * t).collect(),
             offset: self.offset,
+            values: values.collect(),
+            truncated: if reroll.once {
+                self.truncated.clone() * &kept
+            } else {
+                self.truncated.clone()
+            },
+        }
+    }
+
+    /// Distribution of a die that is rolled again every time it shows `threshold` or higher, with
+    /// every roll mapped by `f` and added up. Only `depth` explosions are followed.
+    ///
+    /// Sequences that stop after `k` explosions have `depth - k` less rolls than the longest ones,
+    /// so their frequencies are scaled by the total of a single roll for every missing roll.
+    fn exploded(&self, threshold: i64, depth: u32, f: impl Fn(i64) -> i64) -> Self {
+        let total = self.total();
+        let stop = self.slice(self.offset, threshold).map(&f);
+        let trigger = self.slice(threshold, i64::MAX).map(&f);
+        let trigger_total = trigger.total();
+
+        let mut res = stop.clone().times(total.pow(depth));
+        let mut exploded = FreqGraph::val(0);
+        for k in 1..=depth {
+            exploded = exploded + trigger.clone();
+            let sequences = (exploded.clone() + stop.clone()).times(total.pow(depth - k));
+            res = res.merge(sequences);
+        }
+        res.truncated = trigger_total.pow(depth + 1);
+        res
+    }
+
+    /// Distribution of the sum of the dice that are left after rolling `rolls` dice with `self`
+    /// distribution and dropping the lowest and the highest ones.
+    ///
+    /// Faces are walked from the highest to the lowest, deciding how many of the dice show each
+    /// face. Since the dice are then sorted, it's known which of them are kept, and the number of
+    /// ways to pick the dice is a binomial coefficient.
+    fn kept(&self, rolls: u32, drop_lowest: u32, drop_highest: u32) -> Self {
+        let n = rolls as usize;
+        let kept = (drop_highest as usize)..(n - drop_lowest as usize);
+        let faces = self.values.len();
+
+        let mut binomial = vec![vec![F::one(); n + 1]; n + 1];
+        for i in 1..=n {
+            for j in 1..i {
+                binomial[i][j] = binomial[i - 1][j - 1].clone() + &binomial[i - 1][j];
+            }
+        }
+
+        // ways[assigned][sum] with sum relative to the lowest face of every kept die
+        let len = kept.len() * (faces - 1) + 1;
+        let mut ways = vec![vec![F::zero(); len]; n + 1];
+        ways[0][0] = F::one();
+        for (face, freq) in self.values.iter().enumerate().rev() {
+            let powers: Vec<F> = (0..=n).map(|count| freq.pow(count as u32)).collect();
+            let mut next = vec![vec![F::zero(); len]; n + 1];
+            for assigned in 0..=n {
+                for (sum, w) in ways[assigned]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, w)| !w.is_zero())
+                {
+                    for count in 0..=(n - assigned) {
+                        let lo = usize::max(assigned, kept.start);
+                        let hi = usize::min(assigned + count, kept.end);
+                        let kept_count = hi.saturating_sub(lo);
+                        next[assigned + count][sum + kept_count * face].add_product(
+                            &(w.clone() * &binomial[n - assigned][count]),
+                            &powers[count],
+                        );
+                    }
+                }
+            }
+            ways = next;
+        }
+
+        Self {
+            offset: self.offset * kept.len() as i64,
+            values: ways.swap_remove(n),
+            truncated: truncated_power(self, rolls),
+        }
+    }
+
+    /// Distribution of the highest, or the lowest, of `rolls` dice with `self` distribution.
+    ///
+    /// Combinations where all of the dice are at most `v` are counted by a power, and the highest
+    /// of them is `v` unless all of them are below it. The lowest die is the same from the other
+    /// end
+    fn extreme(&self, rolls: u32, highest: bool) -> Self {
+        let mut faces: Vec<&F> = self.values.iter().collect();
+        if !highest {
+            faces.reverse();
+        }
+        let (mut below, mut all_below) = (F::zero(), F::zero());
+        let mut values: Vec<F> = faces
+            .into_iter()
+            .map(|f| {
+                below += f;
+                let all = below.pow(rolls);
+                let res = all.clone() - &all_below;
+                all_below = all;
+                res
+            })
+            .collect();
+        if !highest {
+            values.reverse();
+        }
+        Self {
+            offset: self.offset,
+            values,
+            truncated: truncated_power(self, rolls),
+        }
+    }
+
+    /// Distribution of the highest of two independent outcomes
+    fn best_of_two(&self) -> Self {
+        let mut below = F::zero();
+        let values = self
+            .values
+            .iter()
+            .map(|f| {
+                // Either both are equal, or one of them is lower
+                let res = f.clone() * &(f.clone() + &below + &below);
+                below += f;
+                res
+            })
+            .collect();
+        Self {
+            offset: self.offset,
+            values,
+            truncated: truncated_power(self, 2),
+        }
+    }
+
+    /// Distribution of the lowest of two independent outcomes
+    fn worst_of_two(&self) -> Self {
+        let mut above = F::zero();
+        let mut values: Vec<F> = self
+            .values
+            .iter()
+            .rev()
+            .map(|f| {
+                let res = f.clone() * &(f.clone() + &above + &above);
+                above += f;
+                res
+            })
+            .collect();
+        values.reverse();
+        Self {
+            offset: self.offset,
+            values,
+            truncated: truncated_power(self, 2),
+        }
+    }
+
+    fn times(self, t: F) -> Self {
+        Self {
+            values: self.values.into_iter().map(|f| f * &t).collect(),
+            offset: self.offset,
+            truncated: self.truncated * &t,
         }
     }
 }
 
-impl std::ops::Add for FreqGraph {
-    type Output = Self;
+impl<F: Freq> FreqGraph<F> {
+    /// Distribution of `self / rhs`. Fails if the divisor has any chance to be zero
+    fn divide(self, rhs: Self, rounding: Rounding) -> Result<Self, EvalError> {
+        let outcomes = |graph: &Self| {
+            let offset = graph.offset;
+            graph
+                .values
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| !f.is_zero())
+                .map(move |(n, f)| (n as i64 + offset, f.clone()))
+                .collect::<Vec<_>>()
+        };
+        let (left, right) = (outcomes(&self), outcomes(&rhs));
+        if right.iter().any(|(v, _)| *v == 0) {
+            return Err(EvalError::DivisionByZero);
+        }
 
-    fn add(self, rhs: Self) -> Self::Output {
-        let len = self.values.len() + rhs.values.len() - 1;
-        let mut values = Vec::with_capacity(len);
-        values.resize(len, 0f64);
+        let quotients = || {
+            left.iter()
+                .flat_map(|l| right.iter().map(move |r| (l, r)))
+                .map(|((lv, lf), (rv, rf))| (rounding.div(*lv, *rv), lf.clone() * rf))
+        };
+        let offset = quotients().map(|(q, _)| q).min().unwrap_or(0);
+        let end = quotients()
+            .map(|(q, _)| q)
+            .max()
+            .map_or(offset, |max| max + 1);
+        let mut values = vec![F::zero(); (end - offset) as usize];
+        for (q, f) in quotients() {
+            values[(q - offset) as usize] += f;
+        }
+        Ok(Self {
+            offset,
+            truncated: truncated_pair(&self, &rhs),
+            values,
+        })
+    }
+}
+
+impl<F: Freq> FreqGraph<F> {
+    /// Mixture of `then` and `otherwise` weighted by the chance of `cond` to be non-zero and zero.
+    /// A branch may be omitted if it has no chance to be taken
+    fn branch(cond: &Self, then: Option<Self>, otherwise: Option<Self>) -> Self {
+        let zero = cond.slice(0, 1).total();
+        let nonzero = cond.total() - &cond.truncated - &zero;
+        let branches = then
+            .map(|then| (nonzero, then))
+            .into_iter()
+            .chain(otherwise.map(|otherwise| (zero, otherwise)))
+            .collect();
+        FreqGraph::mixture(branches, cond.truncated.clone())
+    }
+
+    /// Mixture of the graphs, each one taken with the chance proportional to its weight. Weight
+    /// `truncated` is the chance of an outcome that was cut off.
+    ///
+    /// Graphs are scaled to the same total, the least common multiple of their totals.
+    fn mixture(parts: Vec<(F, Self)>, truncated: F) -> Self {
+        let common = parts
+            .iter()
+            .fold(F::one(), |acc, (_, graph)| acc.lcm(&graph.total()));
+
+        let mut res: Option<Self> = None;
+        for (weight, graph) in parts {
+            let total = graph.total();
+            let part = graph.times(weight * &(common.clone() / total));
+            res = Some(match res {
+                Some(res) => res.merge(part),
+                None => part,
+            });
+        }
+        let mut res = res.unwrap_or_else(|| FreqGraph::val(0).times(F::zero()));
+        res.truncated += truncated * &common;
+        res
+    }
+
+    /// Bernoulli distribution of the comparison being true, `1`, or false, `0`
+    fn compare(self, rhs: Self, cmp: Cmp) -> Self {
+        let mut values = vec![F::zero(); 2];
         for (ln, lfreq) in self.values.iter().enumerate() {
             for (rn, rfreq) in rhs.values.iter().enumerate() {
-                values[ln + rn] = values[ln + rn] + lfreq * rfreq;
+                let res = cmp.test(ln as i64 + self.offset, rn as i64 + rhs.offset);
+                values[res as usize].add_product(lfreq, rfreq);
             }
         }
+        Self {
+            offset: 0,
+            truncated: truncated_pair(&self, &rhs),
+            values,
+        }
+    }
+}
+
+/// Truncated frequency of `n` independent outcomes of `graph`, that is, of all combinations where
+/// at least one of the outcomes is truncated: `total^n - kept^n`
+fn truncated_power<F: Freq>(graph: &FreqGraph<F>, n: u32) -> F {
+    let total = graph.total();
+    let kept = total.clone() - &graph.truncated;
+    (0..n).fold(F::zero(), |acc, i| {
+        acc + &(graph.truncated.clone() * &total.pow(i) * &kept.pow(n - 1 - i))
+    })
+}
+
+/// Truncated frequency of a combination of two independent outcomes
+fn truncated_pair<F: Freq>(left: &FreqGraph<F>, right: &FreqGraph<F>) -> F {
+    left.truncated.clone() * &right.total() + &((left.total() - &left.truncated) * &right.truncated)
+}
+
+impl<F: Freq> std::ops::Add for FreqGraph<F> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        let values = F::convolve(&self.values, &rhs.values);
         Self {
             offset: self.offset + rhs.offset,
+            truncated: truncated_pair(&self, &rhs),
             values,
         }
     }
 }
 
-impl std::ops::Sub for FreqGraph {
+impl<F: Freq> std::ops::Sub for FreqGraph<F> {
     type Output = Self;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        let len = self.values.len() + rhs.values.len() - 1;
-        let mut values = Vec::with_capacity(len);
-        values.resize(len, 0f64);
-        for (ln, lfreq) in self.values.iter().enumerate() {
-            for (rn, rfreq) in rhs.values.iter().enumerate() {
-                values[ln + rn] = values[ln + rn] + lfreq * rfreq;
-            }
-        }
-        // TODO: Values are probably wrong here
+        // Same as adding the mirrored right hand side
+        let rlen = rhs.values.len();
+        let mirrored: Vec<F> = rhs.values.iter().rev().cloned().collect();
+        let values = F::convolve(&self.values, &mirrored);
         Self {
-            offset: self.offset - rhs.offset,
+            offset: self.offset - (rhs.offset + rlen as i64 - 1),
+            truncated: truncated_pair(&self, &rhs),
             values,
         }
     }
 }
 
-impl std::ops::Mul for FreqGraph {
+impl<F: Freq> std::ops::Mul for FreqGraph<F> {
     type Output = Self;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        let max = (self.values.len() as i64 - 1 + self.offset)
-            * (rhs.values.len() as i64 - 1 + rhs.offset)
-            + 1;
-        let offset = self.offset * rhs.offset;
-        let len = max - offset;
-        let mut values = Vec::with_capacity(len as usize);
-        values.resize(len as usize, 0f64);
+        // With negative values any of the corners can be the lowest or the highest
+        let lmax = self.values.len() as i64 - 1 + self.offset;
+        let rmax = rhs.values.len() as i64 - 1 + rhs.offset;
+        let corners = [
+            self.offset * rhs.offset,
+            self.offset * rmax,
+            lmax * rhs.offset,
+            lmax * rmax,
+        ];
+        let offset = *corners.iter().min().unwrap();
+        let len = *corners.iter().max().unwrap() - offset + 1;
+        let mut values = vec![F::zero(); len as usize];
         for (ln, lfreq) in self.values.iter().enumerate() {
             for (rn, rfreq) in rhs.values.iter().enumerate() {
                 let res = ((ln as i64 + self.offset) * (rn as i64 + rhs.offset) - offset) as usize;
-                values[res] = values[res] + lfreq * rfreq;
+                values[res].add_product(lfreq, rfreq);
             }
         }
-        Self { offset, values }
+        Self {
+            offset,
+            truncated: truncated_pair(&self, &rhs),
+            values,
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 enum Op {
     Add,
     Sub,
     Mul,
+    Div(Rounding),
+    /// Comparison results in either `1` or `0`
+    Cmp(Cmp),
+}
+
+/// How the result of an integer division is rounded
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum Rounding {
+    /// `/`, the way D&D rounds
+    Floor,
+    /// `/^`
+    Ceil,
+    /// `/~`, to the nearest integer, halves away from zero
+    Round,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+impl Op {
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div(Rounding::Floor) => "/",
+            Op::Div(Rounding::Ceil) => "/^",
+            Op::Div(Rounding::Round) => "/~",
+            Op::Cmp(Cmp::Lt) => "<",
+            Op::Cmp(Cmp::Le) => "<=",
+            Op::Cmp(Cmp::Gt) => ">",
+            Op::Cmp(Cmp::Ge) => ">=",
+            Op::Cmp(Cmp::Eq) => "==",
+        }
+    }
+}
+
+impl Rounding {
+    fn div(self, left: i64, right: i64) -> i64 {
+        let (quot, rem) = (left / right, left % right);
+        // Truncated division already rounds towards zero
+        let away = if (left < 0) == (right < 0) { 1 } else { -1 };
+        match self {
+            Rounding::Floor if rem != 0 && away < 0 => quot - 1,
+            Rounding::Ceil if rem != 0 && away > 0 => quot + 1,
+            Rounding::Round if 2 * rem.abs() >= right.abs() => quot + away,
+            _ => quot,
+        }
+    }
+}
+
+/// Comparison of an outcome against a number
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+}
+
+impl Cmp {
+    fn test(self, left: i64, right: i64) -> bool {
+        match self {
+            Cmp::Lt => left < right,
+            Cmp::Le => left <= right,
+            Cmp::Gt => left > right,
+            Cmp::Ge => left >= right,
+            Cmp::Eq => left == right,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Cond {
+    cmp: Cmp,
+    value: u32,
+}
+
+impl Cond {
+    fn matches(self, face: i64) -> bool {
+        self.cmp.test(face, self.value as i64)
+    }
+
+    /// Number of faces of a die with `edges` faces that match, without walking all of them
+    fn count(self, edges: u32) -> u32 {
+        let below = |v: u32| u32::min(v, edges);
+        match self.cmp {
+            Cmp::Lt => below(self.value.saturating_sub(1)),
+            Cmp::Le => below(self.value),
+            Cmp::Gt => edges - below(self.value),
+            Cmp::Ge => edges - below(self.value.saturating_sub(1)),
+            Cmp::Eq => (1..=edges).contains(&self.value) as u32,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum Func {
+    /// Roll twice and take the highest
+    Adv,
+    /// Roll twice and take the lowest
+    Dis,
+}
+
+impl Func {
+    fn name(self) -> &'static str {
+        match self {
+            Func::Adv => "adv",
+            Func::Dis => "dis",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 enum Val {
     Num(u32),
     Die(Die),
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 struct Die {
     edges: u32,
     rolls: u32,
     drop_lowest: u32,
     drop_highest: u32,
+    reroll: Option<Reroll>,
+    explode: Option<Explode>,
+    success: Option<Success>,
+}
+
+/// Dice pool, counts successes instead of adding up the faces
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Success {
+    success: Cond,
+    /// Failures are subtracted from the successes
+    failure: Option<Cond>,
+}
+
+impl Success {
+    fn count(self, face: i64) -> i64 {
+        let success = self.success.matches(face) as i64;
+        let failure = matches!(self.failure, Some(f) if f.matches(face)) as i64;
+        success - failure
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Reroll {
+    /// Reroll at most once, otherwise keep rerolling while the condition matches
+    once: bool,
+    cond: Cond,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Explode {
+    /// Compounding dice are added up into a single die. It makes no difference for the sum, but
+    /// they are still told apart
+    compound: bool,
+    /// Lowest face that triggers an explosion
+    threshold: u32,
 }
 
 impl Die {
@@ -221,6 +1142,664 @@ impl Die {
             rolls: 1,
             drop_lowest: 0,
             drop_highest: 0,
+            reroll: None,
+            explode: None,
+            success: None,
+        }
+    }
+
+    fn throw<R: Rng + ?Sized>(&self, rng: &mut R) -> Trace {
+        let mut dice: Vec<DieRoll> = (0..self.rolls).map(|_| self.throw_single(rng)).collect();
+        // Ties are dropped in the order they were rolled
+        let mut order: Vec<usize> = (0..dice.len()).collect();
+        order.sort_by_key(|n| dice[*n].value);
+        let kept = self.drop_lowest as usize..(self.rolls - self.drop_highest) as usize;
+        for (rank, n) in order.into_iter().enumerate() {
+            dice[n].dropped = !kept.contains(&rank);
+        }
+        Trace::Dice {
+            value: dice.iter().filter(|d| !d.dropped).map(|d| d.value).sum(),
+            dice,
+        }
+    }
+
+    fn throw_single<R: Rng + ?Sized>(&self, rng: &mut R) -> DieRoll {
+        let mut rerolled = Vec::new();
+        let mut roll = || {
+            let mut face = || rng.gen_range(1, self.edges as i64 + 1);
+            let mut res = face();
+            match self.reroll {
+                Some(Reroll { once: true, cond }) if cond.matches(res) => {
+                    rerolled.push(res);
+                    res = face()
+                }
+                Some(Reroll { once: false, cond }) => {
+                    while cond.matches(res) {
+                        rerolled.push(res);
+                        res = face()
+                    }
+                }
+                _ => {}
+            }
+            res
+        };
+        let mut faces = vec![roll()];
+        if let Some(Explode { threshold, .. }) = self.explode {
+            while faces[faces.len() - 1] >= threshold as i64
+                && faces.len() <= MAX_EXPLOSIONS as usize
+            {
+                faces.push(roll());
+            }
+        }
+
+        let count = |v| self.success.map_or(v, |s| s.count(v));
+        let value = match self.explode {
+            // Every exploded roll is counted on its own
+            Some(Explode {
+                compound: false, ..
+            }) => faces.iter().copied().map(count).sum(),
+            _ => count(faces.iter().sum()),
+        };
+        DieRoll {
+            faces,
+            rerolled,
+            value,
+            dropped: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use num_bigint::BigUint;
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    fn analyze(expr: &str) -> FreqGraph {
+        Hand::from_str(expr)
+            .expect("Unable to parse valid expr")
+            .analyze()
+            .expect("Unable to analyze valid expr")
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_keep_highest() {
+        let graph = analyze("2d20kh1");
+        assert_eq!(graph.offset, 1);
+        let expected: Vec<f64> = (1..=20).map(|v| (2 * v - 1) as f64).collect();
+        assert_eq!(graph.values, expected);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_large_pool_tails() {
+        let graph = analyze("100d6");
+        assert_eq!((graph.values[0], graph.values[1]), (1f64, 100f64));
+        let stats = graph.statistics().unwrap();
+        assert_eq!((stats.min, stats.max), (100, 600));
+        assert_eq!(analyze("64d6").statistics().unwrap().min, 64);
+        assert_eq!(analyze("70d20").statistics().unwrap().max, 1400);
+
+        // Frequencies past the range of floats, only the exact counts still hold them
+        for expr in &["400d6", "300d20kh1", "1000d100kh1"] {
+            let hand = Hand::from_str(expr).unwrap();
+            assert_eq!(hand.analyze().unwrap_err(), EvalError::Overflow, "{}", expr);
+            let options = AnalyzeOptions::default();
+            assert!(hand.analyze_with::<BigUint>(&options).is_ok(), "{}", expr);
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_keep_extreme() {
+        let graph = analyze("3d6kh1");
+        assert_eq!(graph.offset, 1);
+        assert_eq!(graph.values, vec![1f64, 7f64, 19f64, 37f64, 61f64, 91f64]);
+        assert_eq!(
+            analyze("3d6kl1").values,
+            vec![91f64, 61f64, 37f64, 19f64, 7f64, 1f64]
+        );
+        assert_eq!(analyze("4d8dl3").values, analyze("4d8kh1").values);
+        assert!(Hand::from_str("200d20kh1").unwrap().analyze().is_ok());
+
+        // Same as walking every face for every number of dice
+        let single = FreqGraph::<BigUint>::uniform(6).rerolled(Reroll {
+            once: true,
+            cond: Cond {
+                cmp: Cmp::Le,
+                value: 2,
+            },
+        });
+        for rolls in 2..6 {
+            let highest = single.extreme(rolls, true);
+            assert_eq!(highest.values, single.kept(rolls, rolls - 1, 0).values);
+            let lowest = single.extreme(rolls, false);
+            assert_eq!(lowest.values, single.kept(rolls, 0, rolls - 1).values);
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_drop_lowest() {
+        let graph = analyze("4d6dl1");
+        assert_eq!(graph.offset, 3);
+        assert_eq!(graph.values.len(), 16);
+        assert_eq!(graph.values.iter().sum::<f64>(), 1296f64);
+        assert_eq!(graph.values[0], 1f64);
+        assert_eq!(graph.values[15], 21f64);
+        // Same as keeping the highest 3
+        assert_eq!(graph.values, analyze("4d6kh3").values);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_advantage() {
+        let expected: Vec<f64> = (1..=20).map(|v| (2 * v - 1) as f64).collect();
+        assert_eq!(analyze("adv(d20)").values, expected);
+        assert_eq!(analyze("d20a").values, expected);
+
+        let graph = analyze("adv(2d2)");
+        assert_eq!(graph.offset, 2);
+        assert_eq!(graph.values, vec![1f64, 8f64, 7f64]);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_disadvantage() {
+        let expected = vec![7f64, 5f64, 3f64, 1f64];
+        assert_eq!(analyze("dis(d4)").values, expected);
+        assert_eq!(analyze("d4d").values, expected);
+        assert_eq!(analyze("dis(d4 - 1)").offset, 0);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_reroll() {
+        let graph = analyze("d6r<3");
+        assert_eq!(graph.offset, 1);
+        assert_eq!(graph.values, vec![2f64, 2f64, 8f64, 8f64, 8f64, 8f64]);
+
+        let graph = analyze("d20rr1");
+        assert_eq!(graph.offset, 2);
+        assert_eq!(graph.values, vec![1f64; 19]);
+
+        // Great Weapon Fighting
+        let graph = analyze("2d6r<3");
+        assert_eq!(graph.offset, 2);
+        assert_eq!(graph.values.iter().sum::<f64>(), 36f64.powi(2));
+        assert_eq!(graph.values[0], 4f64);
+        assert_eq!(graph.values[10], 64f64);
+
+        for cmp in [Cmp::Lt, Cmp::Le, Cmp::Gt, Cmp::Ge, Cmp::Eq].iter() {
+            for value in 0..=8 {
+                let cond = Cond { cmp: *cmp, value };
+                let matching = (1..=6).filter(|&face| cond.matches(face)).count();
+                assert_eq!(cond.count(6) as usize, matching, "{:?}", cond);
+            }
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_success() {
+        // Binomial with p = 0.4
+        let graph = analyze("3d10s>=7");
+        assert_eq!(graph.offset, 0);
+        assert_eq!(graph.values, vec![216f64, 432f64, 288f64, 64f64]);
+
+        let graph = analyze("2d6s>=5f1");
+        assert_eq!(graph.offset, -2);
+        assert_eq!(graph.values, vec![1f64, 6f64, 13f64, 12f64, 4f64]);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_success_exploding() {
+        let options = AnalyzeOptions { explosion_depth: 1 };
+        // Compounding die only counts the total once
+        let graph = Hand::from_str("d2!!s2")
+            .unwrap()
+            .analyze_with::<f64>(&options)
+            .unwrap();
+        assert_eq!(graph.offset, 0);
+        assert_eq!(graph.values, vec![3f64]);
+        assert_eq!(graph.truncated, 1f64);
+        // Otherwise every roll is a success of its own
+        let graph = Hand::from_str("d2!s2")
+            .unwrap()
+            .analyze_with::<f64>(&options)
+            .unwrap();
+        assert_eq!(graph.values, vec![2f64, 1f64]);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_exploding() {
+        let options = AnalyzeOptions { explosion_depth: 2 };
+        let graph = Hand::from_str("d2!")
+            .unwrap()
+            .analyze_with::<f64>(&options)
+            .unwrap();
+        // 1 | 2+1 | 2+2+1, and 2+2+2 is cut off
+        assert_eq!(graph.offset, 1);
+        assert_eq!(graph.values, vec![4f64, 0f64, 2f64, 0f64, 1f64]);
+        assert_eq!(graph.truncated, 1f64);
+
+        let graph = Hand::from_str("d6!>=5 + 1")
+            .unwrap()
+            .analyze_with::<f64>(&options)
+            .unwrap();
+        assert_eq!(graph.total(), 6f64.powi(3));
+        assert_eq!(graph.truncated, 2f64.powi(3));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn throw_exploding() {
+        for _ in 0..100 {
+            let res = Hand::from_str("d2!!").unwrap().throw().unwrap();
+            assert!(res >= 1 && res <= 1 + 2 * MAX_EXPLOSIONS as i64);
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_sub() {
+        let graph = analyze("d20 - d4");
+        assert_eq!(graph.offset, -3);
+        assert_eq!(graph.values.len(), 23);
+        // Symmetric, unlike d20 + d4 shifted
+        assert_eq!(graph.values[0], 1f64);
+        assert_eq!(graph.values[1], 2f64);
+        assert_eq!(graph.values[21], 2f64);
+        assert_eq!(graph.values[22], 1f64);
+        assert!(graph.values[3..20].iter().all(|f| *f == 4f64));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_mul_negative() {
+        let graph = analyze("(d3 - 2) * (d3 - 2)");
+        assert_eq!(graph.offset, -1);
+        assert_eq!(graph.values, vec![2f64, 5f64, 2f64]);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_dynamic_count() {
+        // One die or two dice
+        let graph = analyze("d2d4");
+        assert_eq!(graph.offset, 1);
+        let expected = vec![4f64, 5f64, 6f64, 7f64, 4f64, 3f64, 2f64, 1f64];
+        assert_eq!(graph.values, expected);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_div() {
+        let graph = analyze("d6 / 2");
+        assert_eq!(graph.offset, 0);
+        assert_eq!(graph.values, vec![1f64, 2f64, 2f64, 1f64]);
+        let graph = analyze("d6 /^ 2");
+        assert_eq!(graph.offset, 1);
+        assert_eq!(graph.values, vec![2f64, 2f64, 2f64]);
+        let graph = analyze("(d4 - 3) /~ 2");
+        assert_eq!(graph.offset, -1);
+        assert_eq!(graph.values, vec![2f64, 1f64, 1f64]);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn rounding() {
+        let cases = [
+            (7, 2, 3, 4, 4),
+            (-7, 2, -4, -3, -4),
+            (7, -2, -4, -3, -4),
+            (-7, -2, 3, 4, 4),
+            (8, 3, 2, 3, 3),
+            (6, 3, 2, 2, 2),
+        ];
+        for (l, r, floor, ceil, round) in cases.iter() {
+            assert_eq!(Rounding::Floor.div(*l, *r), *floor);
+            assert_eq!(Rounding::Ceil.div(*l, *r), *ceil);
+            assert_eq!(Rounding::Round.div(*l, *r), *round);
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn division_by_zero() {
+        let hand = || Hand::from_str("d6 / (d2 - 1)").unwrap();
+        assert_eq!(hand().analyze().unwrap_err(), EvalError::DivisionByZero);
+        let hand = hand();
+        let thrown = (0..100).map(|_| hand.throw()).collect::<Vec<_>>();
+        assert!(thrown.contains(&Err(EvalError::DivisionByZero)));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn overflow() {
+        let hand = Hand::from_str("4294967295 * 4294967295").unwrap();
+        assert_eq!(hand.throw().unwrap_err(), EvalError::Overflow);
+        assert_eq!(hand.analyze().unwrap_err(), EvalError::Overflow);
+        // Value of the comparison is small, but its sides are not
+        let hand = Hand::from_str("4194304 * 4294967295 > 0").unwrap();
+        assert_eq!(hand.throw().unwrap_err(), EvalError::Overflow);
+        assert_eq!(hand.analyze().unwrap_err(), EvalError::Overflow);
+        let hand = Hand::from_str("2097152 * 4294967295 - 1").unwrap();
+        assert_eq!(hand.throw().unwrap(), (1 << 53) - 2097153);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn limits() {
+        let exceeded = |limit, value, max| EvalError::LimitExceeded { limit, value, max };
+        let hand = Hand::from_str("(1001)d6").unwrap();
+        assert_eq!(hand.throw(), Err(exceeded(Limit::Dice, 1001, 1000)));
+        assert_eq!(
+            hand.analyze().unwrap_err(),
+            exceeded(Limit::Dice, 1001, 1000)
+        );
+        // Only the analysis has to hold every outcome
+        let hand = Hand::from_str("d10000 * 1000").unwrap();
+        assert!(hand.throw().is_ok());
+        let support = 10_000_000 - 1000 + 1;
+        let err = hand.analyze().unwrap_err();
+        assert_eq!(err, exceeded(Limit::Support, support, 100_000));
+        assert!(Hand::from_str("1000 + d6 * 10").unwrap().analyze().is_ok());
+
+        let limits = Limits {
+            max_operations: 21,
+            ..Limits::default()
+        };
+        // Every sub-expression and every die rolled
+        let hand = Hand::parse_with("4d6 + 4d6", &limits).unwrap();
+        assert!(hand.throw().is_ok());
+        let hand = Hand::parse_with("4d6 + 4d6 + 1", &limits).unwrap();
+        assert_eq!(hand.throw(), Err(exceeded(Limit::Operations, 22, 21)));
+        assert!(matches!(
+            hand.analyze(),
+            Err(EvalError::LimitExceeded {
+                limit: Limit::Operations,
+                ..
+            })
+        ));
+
+        // Keeping some of many dice walks every count of every face
+        for expr in &["1000d100kh2", "1000d100dl1", "500d100kh250", "300d20kh150"] {
+            let hand = Hand::from_str(expr).unwrap();
+            assert!(
+                matches!(
+                    hand.analyze(),
+                    Err(EvalError::LimitExceeded {
+                        limit: Limit::Operations,
+                        ..
+                    })
+                ),
+                "{}",
+                expr
+            );
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_cmp() {
+        // Chance to hit AC 15
+        let graph = analyze("d20 + 7 >= 15");
+        assert_eq!(graph.offset, 0);
+        assert_eq!(graph.values, vec![7f64, 13f64]);
+        assert_eq!(analyze("d6 == d6").values, vec![30f64, 6f64]);
+        assert_eq!(analyze("d4 < 0").values, vec![4f64, 0f64]);
+        // Comparison right after a die means the same as a spaced one
+        assert_eq!(analyze("2d20>=15").values, vec![91f64, 309f64]);
+        let pairs = [
+            ("d6==d6", "d6 == d6"),
+            ("d20>=d4", "d20 >= d4"),
+            ("2d20>=15", "2d20 >= 15"),
+            ("d8<3", "d8 < 3"),
+        ];
+        for (unspaced, spaced) in pairs.iter() {
+            assert_eq!(
+                analyze(unspaced).values,
+                analyze(spaced).values,
+                "{}",
+                unspaced
+            );
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_ternary() {
+        // Attack that hits AC 15 on 11+, and deals 2d6 + 4 damage
+        let graph = analyze("d20 + 4 >= 15 ? 2d6 + 4 : 0");
+        assert_eq!(graph.offset, 0);
+        assert_eq!(graph.values.len(), 17);
+        assert_eq!(graph.total(), 20f64 * 36f64);
+        assert_eq!(graph.values[0], 10f64 * 36f64);
+        assert_eq!(graph.values[6], 10f64);
+        assert_eq!(graph.values[11], 60f64);
+        // Branch that can't be taken is skipped
+        assert_eq!(analyze("0 ? 1 / (d2 - 1) : 1").values, vec![1f64]);
+        // Branches share the total of the larger one, so they fit wherever it does
+        for expr in &["d2 == 1 ? 150d6 : 300d6", "attack(d20 vs 10, 150d6)"] {
+            assert!(Hand::from_str(expr).unwrap().analyze().is_ok(), "{}", expr);
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_attack() {
+        let mean = |graph: &FreqGraph| {
+            graph
+                .values
+                .iter()
+                .enumerate()
+                .map(|(n, f)| (n as i64 + graph.offset) as f64 * f)
+                .sum::<f64>()
+                / graph.total()
+        };
+        // Hits on 8-19, crits on 20 for 4d6 + 4
+        let graph = analyze("attack(d20 + 7 vs 15, 2d6 + 4)");
+        assert_eq!(graph.offset, 0);
+        assert_eq!(graph.values.len(), 29);
+        assert_eq!(graph.values[0] / graph.total(), 7f64 / 20f64);
+        assert!((mean(&graph) - (12f64 * 11f64 + 18f64) / 20f64).abs() < 1e-9);
+        // Crits on 19-20, hits on 8-18
+        let graph = analyze("attack(d20 + 7 vs 15, 2d6 + 4, 19)");
+        assert!((mean(&graph) - (11f64 * 11f64 + 2f64 * 18f64) / 20f64).abs() < 1e-9);
+        // Only natural 20 hits, and natural 1 misses even with a huge bonus
+        assert_eq!(
+            analyze("attack(d20 vs 30, 5)").values,
+            vec![19f64, 0f64, 0f64, 0f64, 0f64, 1f64]
+        );
+        assert_eq!(
+            analyze("attack(d20 + 30 vs 10, 1)").values,
+            vec![1f64, 19f64]
+        );
+        // Count of the dice is doubled, but not the dice that count them
+        let graph = analyze("attack(d20 vs 100, (d2)d4)");
+        assert_eq!(graph.offset, 0);
+        assert_eq!(graph.values.len(), 17);
+        assert_eq!(graph.values[0] / graph.total(), 19f64 / 20f64);
+        assert_eq!(
+            analyze("attack(d20 vs 100, (d2)d1, 2)").values,
+            vec![2f64, 0f64, 19f64, 0f64, 19f64]
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn analyze_exact() {
+        let exact = |expr| {
+            Hand::from_str(expr)
+                .unwrap()
+                .analyze_with::<BigUint>(&AnalyzeOptions::default())
+                .unwrap()
+        };
+        // Far beyond the integers that f64 can hold exactly
+        let graph = exact("100d6");
+        assert_eq!(graph.total(), BigUint::from(6u32).pow(100));
+        assert_eq!(graph.values[0], BigUint::from(1u32));
+        assert_eq!(graph.values[1], BigUint::from(100u32));
+        assert_eq!(graph.values[2], BigUint::from(100u32 * 101 / 2));
+        let approx = analyze("100d6");
+        let (mid, total) = (&graph.values[250], graph.total());
+        assert!((mid.ratio(&total) - approx.values[250] / approx.total()).abs() < 1e-12);
+
+        // Hits and crits are scaled to the crits, that roll twice as many dice
+        let graph = exact("attack(d20 + 7 vs 15, 2d6 + 4)");
+        assert_eq!(graph.total(), BigUint::from(20u32 * 6u32.pow(4)));
+        assert_eq!(graph.values[0], BigUint::from(7u32 * 6u32.pow(4)));
+        assert_eq!(exact("d2 == 1 ? d4 : d6").total(), BigUint::from(2u32 * 12));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn throw_attack() {
+        for _ in 0..100 {
+            let damage = Hand::from_str("attack(d20 vs 15, 2d6 + 1)")
+                .unwrap()
+                .throw()
+                .unwrap();
+            assert!(damage == 0 || (3..=25).contains(&damage));
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn throw_keep_lowest() {
+        for _ in 0..100 {
+            let res = Hand::from_str("3d4kl2").unwrap().throw().unwrap();
+            assert!((2..=8).contains(&res));
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn throw_seeded() {
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha8Rng;
+
+        let hand = Hand::from_str("4d6kh3 + d20! + attack(d20 + 5 vs 15, 2d6)").unwrap();
+        let throw = |seed| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            (0..20)
+                .map(|_| hand.throw_with(&mut rng).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(throw(7), throw(7));
+        assert_ne!(throw(7), throw(8));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn throw_n() {
+        let hand = Hand::from_str("2d6 + 1").unwrap();
+        let results = hand.throw_n(200).unwrap();
+        assert_eq!(results.len(), 200);
+        assert!(results.iter().all(|r| (3..=13).contains(r)));
+        // Same hand is thrown again, as well as its copy
+        assert!((3..=13).contains(&hand.throw().unwrap()));
+        assert_eq!(hand.clone().throw_n(0).unwrap(), Vec::<i64>::new());
+        // Any failed throw fails the whole batch
+        assert_eq!(
+            Hand::from_str("d6 / (d1 - 1)").unwrap().throw_n(3),
+            Err(EvalError::DivisionByZero)
+        );
+    }
+}
+
+/// Property tests: analyzed distributions must match the distribution of thrown results
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod sampled {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const SAMPLES: usize = 10_000;
+
+    fn leaf() -> impl Strategy<Value = String> {
+        prop_oneof![
+            (0u32..4).prop_map(|n| n.to_string()),
+            (2u32..7).prop_map(|d| format!("d{}", d)),
+            (1u32..4, 2u32..7).prop_map(|(n, d)| format!("{}d{}", n, d)),
+            prop::sample::select(vec![
+                "4d6kh3", "3d4dl1", "d20a", "d8d", "d6r<3", "d4rr1", "d6!", "d4!!", "5d6s>4f1",
+            ])
+            .prop_map(String::from),
+        ]
+    }
+
+    fn expr() -> impl Strategy<Value = String> {
+        leaf().prop_recursive(3, 8, 2, |inner| {
+            prop_oneof![
+                (
+                    inner.clone(),
+                    prop::sample::select(vec!["+", "-", "*", ">=", "<", "=="]),
+                    inner.clone()
+                )
+                    .prop_map(|(l, op, r)| format!("({} {} {})", l, op, r)),
+                // Divisor is a die, so it's never zero
+                (
+                    inner.clone(),
+                    prop::sample::select(vec!["/", "/^", "/~"]),
+                    1u32..7
+                )
+                    .prop_map(|(l, op, d)| format!("({} {} d{})", l, op, d)),
+                (inner.clone(), 2u32..5).prop_map(|(l, d)| format!("({})d{}", l, d)),
+                (inner.clone(), inner.clone(), inner.clone())
+                    .prop_map(|(c, t, o)| format!("({} ? {} : {})", c, t, o)),
+                (inner.clone(), inner.clone(), inner.clone())
+                    .prop_map(|(r, a, d)| format!("attack(d6 + {} vs {}, {}, 5)", r, a, d)),
+                inner.clone().prop_map(|e| format!("adv({})", e)),
+                inner.prop_map(|e| format!("dis({})", e)),
+            ]
+        })
+    }
+
+    /// Largest difference between cumulative distributions, Kolmogorov-Smirnov statistic
+    fn distance(graph: &FreqGraph, samples: &[i64]) -> f64 {
+        let total = graph.total();
+        let min = i64::min(graph.offset, *samples.iter().min().unwrap());
+        let max = i64::max(
+            graph.offset + graph.values.len() as i64,
+            *samples.iter().max().unwrap(),
+        );
+        let (mut expected, mut actual, mut distance) = (0f64, 0f64, 0f64);
+        for v in min..=max {
+            let n = v - graph.offset;
+            if n >= 0 && (n as usize) < graph.values.len() {
+                expected += graph.values[n as usize] / total;
+            }
+            actual += samples.iter().filter(|s| **s == v).count() as f64 / samples.len() as f64;
+            distance = f64::max(distance, (expected - actual).abs());
+        }
+        distance
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn analyze_matches_throw(expr in expr()) {
+            let hand = Hand::from_str(&expr).unwrap();
+            let graph = hand.analyze().unwrap();
+            let samples = hand.throw_n(SAMPLES).unwrap();
+            // Critical value of the test is around 1e-5 significance
+            prop_assert!(distance(&graph, &samples) < 2.5 / (SAMPLES as f64).sqrt());
+        }
+
+        #[test]
+        fn display_parses_back(expr in expr()) {
+            let hand = Hand::from_str(&expr).unwrap();
+            let normalized = hand.to_string();
+            let parsed = Hand::from_str(&normalized).unwrap();
+            prop_assert_eq!(&parsed.expr, &hand.expr, "{} -> {}", expr, normalized);
+            prop_assert_eq!(parsed.to_string(), normalized);
         }
     }
 }
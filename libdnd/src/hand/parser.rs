@@ -1,30 +1,136 @@
-use super::{Die, Expr, Op, Val};
+use super::{
+    Cmp, Cond, Die, Explode, Expr, Func, Limit, Limits, Op, Reroll, Rounding, Success, Val,
+};
 use serde_derive::Serialize;
+use std::iter::Peekable;
 use std::str::FromStr;
 
-#[derive(Debug, Eq, PartialEq, Serialize)]
+/// Error in the expression, about the chars `start..end` of it
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ParseError {
+    #[serde(flatten)]
+    pub kind: ParseErrorKind,
+    pub start: usize,
+    pub end: usize,
+    /// What would be accepted at `start` instead, such as `number` or `'('`
+    pub expected: Vec<&'static str>,
+    /// Description of the error, with the expected set
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<Box<Suggestion>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
 #[serde(tag = "error", rename_all = "snake_case")]
-pub enum ParseError {
-    UnexpectedToken { index: usize, token: char },
-    BadDie { index: usize },
-    IllegalExpression { index: usize },
-    UnmatchedParen { index: usize },
-    EmptyExpression { index: usize },
+pub enum ParseErrorKind {
+    UnexpectedToken {
+        token: char,
+    },
+    BadDie,
+    IllegalExpression,
+    UnmatchedParen,
+    EmptyExpression,
+    DivisionByZero,
+    /// Number that doesn't fit into 32 bits
+    NumberTooLarge,
+    /// Die that goes past the [`Limits`], `value` is more than the `max` allowed
+    LimitExceeded {
+        limit: Limit,
+        value: u64,
+        max: u64,
+    },
+}
+
+/// Fix of an error that can be applied as is: chars `start..end` of the expression are replaced
+/// with `replacement`
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Suggestion {
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+    pub replacement: String,
+}
+
+/// Start of an expression: anything that has a value
+const VALUE: &[&str] = &["number", "die", "'('"];
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, start: usize, end: usize, message: &str) -> Self {
+        ParseError {
+            kind,
+            start,
+            end,
+            expected: Vec::new(),
+            message: message.to_string(),
+            suggestion: None,
+        }
+    }
+
+    /// Error about a single token
+    fn at(kind: ParseErrorKind, token: IndexedToken, message: &str) -> Self {
+        ParseError::new(kind, token.index, token.end, message)
+    }
+
+    /// Adds the expected set to the error and its message
+    fn expecting(mut self, expected: &[&'static str]) -> Self {
+        self.expected = expected.to_vec();
+        if let Some((last, rest)) = expected.split_last() {
+            let expected = match rest {
+                [] => last.to_string(),
+                _ => format!("{} or {}", rest.join(", "), last),
+            };
+            self.message = format!("{}, expected {}", self.message, expected);
+        }
+        self
+    }
+
+    fn suggest(mut self, message: &str, start: usize, end: usize, replacement: &str) -> Self {
+        self.suggestion = Some(Box::new(Suggestion {
+            message: message.to_string(),
+            start,
+            end,
+            replacement: replacement.to_string(),
+        }));
+        self
+    }
+}
+
+/// Parses the whole expression, with every error of it in the order they appear
+pub(super) fn parse(expr: &str, limits: &Limits) -> Result<Expr, Vec<ParseError>> {
+    let (tokens, mut errors) = Tokens::read(expr);
+    errors.extend(tokens.exceeded(limits));
+    errors.sort_by_key(|e| e.start);
+    match tokens.into_expr() {
+        Ok(expr) if errors.is_empty() => Ok(expr),
+        Ok(_) => Err(errors),
+        Err(more) => {
+            errors.extend(more);
+            errors.sort_by_key(|e| e.start);
+            Err(errors)
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
-pub(super) struct Tokens(Vec<IndexedToken>);
+struct Tokens(Vec<IndexedToken>);
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 struct IndexedToken {
     index: usize,
+    /// Index right after the token, the same as `index` for the inserted ones
+    end: usize,
     token: Token,
 }
 
 impl IndexedToken {
+    fn until(self, end: usize) -> Self {
+        IndexedToken { end, ..self }
+    }
+
     fn begin(index: usize) -> Self {
         IndexedToken {
             index,
+            end: index,
             token: Token::Begin,
         }
     }
@@ -32,6 +138,7 @@ impl IndexedToken {
     fn end(index: usize) -> Self {
         IndexedToken {
             index,
+            end: index,
             token: Token::End,
         }
     }
@@ -39,6 +146,7 @@ impl IndexedToken {
     fn operation(index: usize, op: Op) -> Self {
         IndexedToken {
             index,
+            end: index,
             token: Token::Op(op),
         }
     }
@@ -46,9 +154,73 @@ impl IndexedToken {
     fn value(index: usize, val: Val) -> Self {
         IndexedToken {
             index,
+            end: index,
             token: Token::Val(val),
         }
     }
+
+    fn function(index: usize, func: Func) -> Self {
+        IndexedToken {
+            index,
+            end: index,
+            token: Token::Func(func),
+        }
+    }
+
+    fn ternary(index: usize, ternary: Ternary) -> Self {
+        IndexedToken {
+            index,
+            end: index,
+            token: Token::Ternary(ternary),
+        }
+    }
+
+    fn attack(index: usize) -> Self {
+        IndexedToken {
+            index,
+            end: index,
+            token: Token::Attack,
+        }
+    }
+
+    fn separator(index: usize, sep: Sep) -> Self {
+        IndexedToken {
+            index,
+            end: index,
+            token: Token::Sep(sep),
+        }
+    }
+
+    /// Stands in for chars that couldn't be read. It is a value, so it doesn't cause any
+    /// errors of its own
+    fn invalid(index: usize) -> Self {
+        IndexedToken::value(index, Val::Num(1))
+    }
+
+    /// Name of the token in error messages
+    fn describe(&self) -> &'static str {
+        match self.token {
+            // Inserted tokens have no chars
+            Token::Begin if self.index == self.end => "start of expression",
+            Token::End if self.index == self.end => "end of expression",
+            Token::Begin => "'('",
+            Token::End => "')'",
+            Token::Op(_) => "operator",
+            Token::Val(Val::Num(_)) => "number",
+            Token::Val(Val::Die(_)) => "die",
+            Token::Func(_) => "function",
+            Token::Ternary(Ternary::Then) => "'?'",
+            Token::Ternary(Ternary::Else) => "':'",
+            Token::Attack => "'attack'",
+            Token::Sep(Sep::Vs) => "'vs'",
+            Token::Sep(Sep::Comma) => "','",
+        }
+    }
+
+    fn unexpected(&self) -> ParseError {
+        let message = format!("unexpected {}", self.describe());
+        ParseError::at(ParseErrorKind::IllegalExpression, *self, &message)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -57,215 +229,877 @@ enum Token {
     End,
     Op(Op),
     Val(Val),
+    Func(Func),
+    Ternary(Ternary),
+    Attack,
+    Sep(Sep),
 }
 
-impl FromStr for Tokens {
-    type Err = ParseError;
+/// Parts of the conditional expression, `cond ? then : otherwise`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Ternary {
+    Then,
+    Else,
+}
 
-    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+/// Separators of the attack arguments, `attack(roll vs ac, damage, crit)`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Sep {
+    Vs,
+    Comma,
+}
+
+impl Tokens {
+    /// Splits the expression into tokens. Chars that can't be read are replaced with invalid
+    /// tokens and reported, so the rest of the expression is still read and checked
+    fn read(expr: &str) -> (Tokens, Vec<ParseError>) {
+        use ParseErrorKind::*;
+
+        let len = expr.chars().count();
         let mut tokens = Vec::new();
+        let mut errors = Vec::new();
         let mut chars = expr.chars().enumerate().peekable();
         tokens.push(IndexedToken::begin(0));
         while let Some((index, c)) = chars.next() {
             let token = match c {
                 // Digits
-                '0'..='9' => {
-                    let mut num = c as u32 - '0' as u32;
-                    while let Some((_, '0'..='9')) = chars.peek() {
-                        // Advance the iterator
-                        let (_, c) = chars.next().unwrap();
-                        num = num * 10 + c as u32 - '0' as u32
+                '0'..='9' => match read_digits(&mut chars, index, Some(c as u32 - '0' as u32)) {
+                    Ok(num) => IndexedToken::value(index, Val::Num(num.unwrap_or(0))),
+                    Err(err) => {
+                        errors.push(err);
+                        IndexedToken::invalid(index)
                     }
-                    IndexedToken::value(index, Val::Num(num))
-                }
+                },
+                'a' if keyword(&mut chars, "dv") => IndexedToken::function(index, Func::Adv),
+                'd' if keyword(&mut chars, "is") => IndexedToken::function(index, Func::Dis),
+                'a' if keyword(&mut chars, "ttack") => IndexedToken::attack(index),
+                'v' if keyword(&mut chars, "s") => IndexedToken::separator(index, Sep::Vs),
+                ',' => IndexedToken::separator(index, Sep::Comma),
                 // Die
-                'd' => {
-                    let mut num = 0;
-                    while let Some((_, '0'..='9')) = chars.peek() {
-                        // Advance the iterator
-                        let (_, c) = chars.next().unwrap();
-                        num = num * 10 + c as u32 - '0' as u32
-                    }
-                    if num > 0 {
-                        IndexedToken::value(index, Val::Die(Die::new(num)))
-                    } else {
-                        Err(ParseError::BadDie { index })?
+                'd' => match parse_die(index, &mut chars, len, &mut tokens) {
+                    Ok(die) => die,
+                    Err(err) => {
+                        errors.push(err);
+                        IndexedToken::invalid(index)
                     }
-                }
+                },
                 // Skip whitespace
                 ' ' | '\t' | '\n' => continue,
                 '+' => IndexedToken::operation(index, Op::Add),
                 '-' => IndexedToken::operation(index, Op::Sub),
                 '*' => IndexedToken::operation(index, Op::Mul),
+                '/' if keyword(&mut chars, "^") => {
+                    IndexedToken::operation(index, Op::Div(Rounding::Ceil))
+                }
+                '/' if keyword(&mut chars, "~") => {
+                    IndexedToken::operation(index, Op::Div(Rounding::Round))
+                }
+                '/' => IndexedToken::operation(index, Op::Div(Rounding::Floor)),
+                '>' if keyword(&mut chars, "=") => IndexedToken::operation(index, Op::Cmp(Cmp::Ge)),
+                '>' => IndexedToken::operation(index, Op::Cmp(Cmp::Gt)),
+                '<' if keyword(&mut chars, "=") => IndexedToken::operation(index, Op::Cmp(Cmp::Le)),
+                '<' => IndexedToken::operation(index, Op::Cmp(Cmp::Lt)),
+                '=' if keyword(&mut chars, "=") => IndexedToken::operation(index, Op::Cmp(Cmp::Eq)),
+                '?' => IndexedToken::ternary(index, Ternary::Then),
+                ':' => IndexedToken::ternary(index, Ternary::Else),
                 '(' | '[' | '{' => IndexedToken::begin(index),
                 ')' | ']' | '}' => IndexedToken::end(index),
-                token => Err(ParseError::UnexpectedToken { index, token })?,
+                token => {
+                    let message = format!("unexpected '{}'", token);
+                    let err =
+                        ParseError::new(UnexpectedToken { token }, index, index + 1, &message);
+                    errors.push(match token {
+                        'x' | '×' => err.suggest("multiply with '*'", index, index + 1, "*"),
+                        '=' => err.suggest("compare with '=='", index, index + 1, "=="),
+                        _ => err,
+                    });
+                    IndexedToken::invalid(index)
+                }
             };
-            tokens.push(token);
+            tokens.push(token.until(position(&mut chars, len)));
         }
         tokens.push(IndexedToken::end(
             tokens.last().map(|t| t.index).unwrap_or(0),
         ));
-        Ok(Tokens(tokens))
+        (Tokens(tokens), errors)
     }
-}
 
-impl Tokens {
-    pub(super) fn normalize(self) -> Result<Normalized, ParseError> {
-        use Token::*;
-        // Check matching parens
-        {
-            let parens = self.0.iter().try_fold(Vec::new(), |mut acc, it| {
-                match it.token {
-                    Begin => acc.push(it),
-                    End => {
-                        acc.pop()
-                            .ok_or_else(|| ParseError::UnmatchedParen { index: it.index })?;
-                    }
-                    _ => {}
+    /// Errors of the dice with more dice or faces than the `limits` allow, counting the number
+    /// right before a die as well: `4d6`
+    fn exceeded(&self, limits: &Limits) -> Vec<ParseError> {
+        let mut errors = Vec::new();
+        let mut count = None;
+        for token in self.0.iter() {
+            let die = match token.token {
+                Token::Val(Val::Num(n)) => {
+                    count = Some((token.index, n));
+                    continue;
                 }
-                Ok(acc)
-            })?;
-            if let Some(it) = parens.last() {
-                Err(ParseError::UnmatchedParen { index: it.index })?
+                Token::Val(Val::Die(die)) => die,
+                _ => {
+                    count = None;
+                    continue;
+                }
+            };
+            let (index, rolls) = match count.take() {
+                Some((index, n)) => (index, die.rolls.saturating_mul(n)),
+                None => (token.index, die.rolls),
             };
+            let dice = (Limit::Dice, rolls, limits.max_dice, "dice");
+            let faces = (Limit::Faces, die.edges, limits.max_faces, "faces per die");
+            for &(limit, value, max, what) in [dice, faces].iter() {
+                if value > max {
+                    let kind = ParseErrorKind::LimitExceeded {
+                        limit,
+                        value: value as u64,
+                        max: max as u64,
+                    };
+                    let message = format!("at most {} {} are allowed", max, what);
+                    errors.push(ParseError::new(kind, index, token.end, &message));
+                }
+            }
         }
+        errors
+    }
+}
+
+/// Fails with the errors of the chars only, the whole expression is checked by
+/// [`Tokens::into_expr`]
+impl FromStr for Tokens {
+    type Err = Vec<ParseError>;
 
-        Tokens::normalize_recursive(&self.0).map(|(res, _)| res)
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        match Tokens::read(expr) {
+            (tokens, errors) if errors.is_empty() => Ok(tokens),
+            (_, errors) => Err(errors),
+        }
     }
+}
 
-    fn normalize_recursive(
-        tokens: &[IndexedToken],
-    ) -> Result<(Normalized, &[IndexedToken]), ParseError> {
-        use super::Op::*;
-        use super::Val::*;
-        use Token::*;
+/// Index of the next char, or the length of the input at its end
+fn position<I>(chars: &mut Peekable<I>, len: usize) -> usize
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    chars.peek().map_or(len, |(index, _)| *index)
+}
 
-        let mut left = tokens[0];
-        let mut tokens = &tokens[1..];
-        let mut normalized = Vec::new();
+/// Parses the die that starts with the `d` at `index`, along with its suffixes. The count of
+/// dice is taken from the preceding `tokens` if the die keeps or drops some of them
+fn parse_die<I>(
+    index: usize,
+    chars: &mut Peekable<I>,
+    len: usize,
+    tokens: &mut Vec<IndexedToken>,
+) -> Result<IndexedToken, ParseError>
+where
+    I: Iterator<Item = (usize, char)> + Clone,
+{
+    use ParseErrorKind::BadDie;
 
-        loop {
-            // Guranteed to have at least 2 elements: Begin and End
-            let right = tokens[0];
-            match (left.token, right.token) {
-                // Expression start
-                (Begin, Begin) | (Val(_), Begin) | (Op(_), Begin) => {
-                    if let Val(_) = left.token {
-                        normalized.push(NormToken::Op(Mul))
-                    }
-                    let (expr, remaining) = Tokens::normalize_recursive(tokens)?;
-                    normalized.push(NormToken::Expr(expr));
-                    tokens = remaining;
-                    // Treat expression on next iteration as regular value
-                    left = IndexedToken::value(remaining[0].index, Num(0));
-                    continue;
+    let num = read_number(chars)?.unwrap_or(0);
+    if num == 0 {
+        let err = ParseError::new(BadDie, index, position(chars, len), "die has no size")
+            .expecting(&["die size"]);
+        Err(match chars.peek() {
+            Some((_, 'd')) => err.suggest("remove the extra 'd'", index, index + 1, ""),
+            _ => err.suggest("roll a d20", index, index + 1, "d20"),
+        })?
+    }
+    let bad_die = |chars: &mut Peekable<_>, index, message: &str| {
+        ParseError::new(BadDie, index, position(chars, len), message)
+    };
+    let bad_suffix = |chars: &mut Peekable<_>, index, e, message: &str| match e {
+        SuffixError::Invalid => bad_die(chars, index, message),
+        SuffixError::Number(e) => e,
+    };
+    let reroll = parse_reroll(chars, num)
+        .map_err(|e| bad_suffix(chars, index, e, "reroll has to keep some of the faces"))?;
+    let explode = parse_explode(chars, num).map_err(|e| {
+        let message = "explosion has to be triggered by some faces, not all";
+        bad_suffix(chars, index, e, message)
+    })?;
+    let die = Die {
+        reroll,
+        explode,
+        ..Die::new(num)
+    };
+    let too_many = "keeps or drops more dice than there are";
+    let (index, die) = match KeepDrop::parse(chars)? {
+        Some(keep) => {
+            // Keep and drop need to know how many dice are rolled, so the
+            // preceding count is folded into the die itself: `4d6kh3`
+            let (index, rolls) = match tokens.last() {
+                Some(&IndexedToken {
+                    index,
+                    token: Token::Val(Val::Num(n)),
+                    ..
+                }) => {
+                    tokens.pop();
+                    (index, n)
                 }
-                (Begin, End) => Err(ParseError::EmptyExpression { index: left.index })?,
-                (Begin, Val(v)) => normalized.push(NormToken::Val(v)),
-                (Begin, Op(r @ Sub)) | (Begin, Op(r @ Add)) => {
-                    normalized.push(NormToken::Val(Num(0)));
-                    normalized.push(NormToken::Op(r))
+                _ => (index, 1),
+            };
+            let die = keep
+                .apply(die, rolls)
+                .ok_or_else(|| bad_die(chars, index, too_many))?;
+            (index, die)
+        }
+        None => {
+            // Advantage shorthand: `d20a` and `d20d`. Single `d` is only a
+            // suffix if it can't start another die or keep/drop suffix
+            let mut ahead = chars.clone();
+            let keep = match (ahead.next(), ahead.next()) {
+                (Some((_, 'a')), _) => Some(KeepDrop::KeepHighest(1)),
+                (Some((_, 'd')), Some((_, '0'..='9'))) | (Some((_, 'd')), Some((_, 'i'))) => None,
+                (Some((_, 'd')), _) => Some(KeepDrop::KeepLowest(1)),
+                _ => None,
+            };
+            let die = match keep {
+                Some(keep) => {
+                    chars.next();
+                    keep.apply(die, 2)
+                        .ok_or_else(|| bad_die(chars, index, too_many))?
                 }
-                (Begin, Op(Mul)) | (Op(_), Op(_)) => {
-                    Err(ParseError::IllegalExpression { index: right.index })?
+                None => die,
+            };
+            (index, die)
+        }
+    };
+    let success = parse_success(chars).map_err(|e| match e {
+        SuffixError::Invalid => {
+            bad_die(chars, index, "success has no condition").expecting(&["number"])
+        }
+        SuffixError::Number(e) => e,
+    })?;
+    // Success is counted for every die, there is no way to tell which ones to keep
+    if success.is_some() && die.rolls > 1 {
+        Err(bad_die(
+            chars,
+            index,
+            "successes can't be counted when keeping or dropping dice",
+        ))?
+    }
+    Ok(IndexedToken::value(index, Val::Die(Die { success, ..die })))
+}
+
+/// Consumes the rest of the `word` if the input continues with it
+fn keyword<I>(chars: &mut Peekable<I>, word: &str) -> bool
+where
+    I: Iterator<Item = (usize, char)> + Clone,
+{
+    let mut ahead = chars.clone();
+    if word
+        .chars()
+        .all(|c| ahead.next().map(|(_, n)| n) == Some(c))
+    {
+        *chars = ahead;
+        true
+    } else {
+        false
+    }
+}
+
+/// Error of a die suffix: either it doesn't fit the die, or its number is too large
+enum SuffixError {
+    Invalid,
+    Number(ParseError),
+}
+
+impl From<ParseError> for SuffixError {
+    fn from(e: ParseError) -> Self {
+        SuffixError::Number(e)
+    }
+}
+
+/// Reroll suffix of a die with `edges` faces: `r` to reroll once, `rr` to reroll until the
+/// condition stops matching. Errors if the condition would never match, or would never stop
+fn parse_reroll<I>(chars: &mut Peekable<I>, edges: u32) -> Result<Option<Reroll>, SuffixError>
+where
+    I: Iterator<Item = (usize, char)> + Clone,
+{
+    if !keyword(chars, "r") {
+        return Ok(None);
+    }
+    let once = !keyword(chars, "r");
+    let cond = parse_cond(chars)?.ok_or(SuffixError::Invalid)?;
+    let matching = cond.count(edges);
+    if matching == 0 || (!once && matching == edges) {
+        return Err(SuffixError::Invalid);
+    }
+    Ok(Some(Reroll { once, cond }))
+}
+
+/// Success counting suffix of a die: `s>=7`, optionally followed by failures to subtract, `f1`.
+/// Comparison right after a die compares the roll instead, `d20>=d4`, so the pool has a marker
+/// of its own
+fn parse_success<I>(chars: &mut Peekable<I>) -> Result<Option<Success>, SuffixError>
+where
+    I: Iterator<Item = (usize, char)> + Clone,
+{
+    if !keyword(chars, "s") {
+        return Ok(None);
+    }
+    let success = parse_cond(chars)?.ok_or(SuffixError::Invalid)?;
+    let failure = if keyword(chars, "f") {
+        Some(parse_cond(chars)?.ok_or(SuffixError::Invalid)?)
+    } else {
+        None
+    };
+    Ok(Some(Success { success, failure }))
+}
+
+/// Comparison with a number, `<3`, `>=5`, `=1`. Bare number is the same as `=`
+fn parse_cond<I>(chars: &mut Peekable<I>) -> Result<Option<Cond>, ParseError>
+where
+    I: Iterator<Item = (usize, char)> + Clone,
+{
+    let cmp = if keyword(chars, "<=") {
+        Cmp::Le
+    } else if keyword(chars, "<") {
+        Cmp::Lt
+    } else if keyword(chars, ">=") {
+        Cmp::Ge
+    } else if keyword(chars, ">") {
+        Cmp::Gt
+    } else {
+        let _ = keyword(chars, "==") || keyword(chars, "=");
+        Cmp::Eq
+    };
+    Ok(read_number(chars)?.map(|value| Cond { cmp, value }))
+}
+
+/// Exploding suffix of a die with `edges` faces: `!`, compounding `!!` and an optional trigger
+/// condition, `!>5` or `!>=5`. Errors if the die would never stop or never explode
+fn parse_explode<I>(chars: &mut Peekable<I>, edges: u32) -> Result<Option<Explode>, SuffixError>
+where
+    I: Iterator<Item = (usize, char)> + Clone,
+{
+    if !keyword(chars, "!") {
+        return Ok(None);
+    }
+    let compound = keyword(chars, "!");
+    let threshold = if keyword(chars, ">=") {
+        read_number(chars)?.ok_or(SuffixError::Invalid)?
+    } else if keyword(chars, ">") {
+        let above = read_number(chars)?.ok_or(SuffixError::Invalid)?;
+        above.checked_add(1).ok_or(SuffixError::Invalid)?
+    } else {
+        edges
+    };
+    if threshold < 2 || threshold > edges {
+        return Err(SuffixError::Invalid);
+    }
+    Ok(Some(Explode {
+        compound,
+        threshold,
+    }))
+}
+
+/// Number at the start of `chars`, if it starts with a digit
+fn read_number<I>(chars: &mut Peekable<I>) -> Result<Option<u32>, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let start = chars.peek().map_or(0, |(index, _)| *index);
+    read_digits(chars, start, None)
+}
+
+/// Reads the rest of the number that starts at `start`, with the value `num` of the digits that
+/// were already read. All of its digits are read even if it doesn't fit
+fn read_digits<I>(
+    chars: &mut Peekable<I>,
+    start: usize,
+    mut num: Option<u32>,
+) -> Result<Option<u32>, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut too_large = false;
+    let mut end = start + 1;
+    while let Some(&(index, c @ '0'..='9')) = chars.peek() {
+        // Advance the iterator
+        chars.next();
+        end = index + 1;
+        let digit = c as u32 - '0' as u32;
+        match num
+            .unwrap_or(0)
+            .checked_mul(10)
+            .and_then(|n| n.checked_add(digit))
+        {
+            Some(n) => num = Some(n),
+            None => too_large = true,
+        }
+    }
+    if too_large {
+        let kind = ParseErrorKind::NumberTooLarge;
+        let message = format!("number is larger than {}", u32::MAX);
+        return Err(ParseError::new(kind, start, end, &message));
+    }
+    Ok(num)
+}
+
+/// Keep/drop suffix of a die, e.g. `kh3` in `4d6kh3`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum KeepDrop {
+    KeepHighest(u32),
+    KeepLowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+}
+
+impl KeepDrop {
+    /// Consumes the suffix if there is one. Number may be omitted, `kh` is the same as `kh1`
+    fn parse<I>(chars: &mut Peekable<I>) -> Result<Option<Self>, ParseError>
+    where
+        I: Iterator<Item = (usize, char)> + Clone,
+    {
+        let mut ahead = chars.clone();
+        let kind = match (ahead.next(), ahead.next()) {
+            (Some((_, 'k')), Some((_, 'h'))) => KeepDrop::KeepHighest,
+            (Some((_, 'k')), Some((_, 'l'))) => KeepDrop::KeepLowest,
+            (Some((_, 'd')), Some((_, 'h'))) => KeepDrop::DropHighest,
+            (Some((_, 'd')), Some((_, 'l'))) => KeepDrop::DropLowest,
+            _ => return Ok(None),
+        };
+        chars.next();
+        chars.next();
+        Ok(Some(kind(read_number(chars)?.unwrap_or(1))))
+    }
+
+    /// Returns `None` if the suffix doesn't leave at least one die to keep
+    fn apply(self, die: Die, rolls: u32) -> Option<Die> {
+        let (drop_lowest, drop_highest) = match self {
+            KeepDrop::KeepHighest(n) if n > 0 && n <= rolls => (rolls - n, 0),
+            KeepDrop::KeepLowest(n) if n > 0 && n <= rolls => (0, rolls - n),
+            KeepDrop::DropHighest(n) if n < rolls => (0, n),
+            KeepDrop::DropLowest(n) if n < rolls => (n, 0),
+            _ => return None,
+        };
+        Some(Die {
+            rolls,
+            drop_lowest,
+            drop_highest,
+            ..die
+        })
+    }
+}
+
+impl Tokens {
+    /// Builds the whole expression, and reports every error instead of stopping at the first one
+    fn into_expr(self) -> Result<Expr, Vec<ParseError>> {
+        let mut errors = Vec::new();
+        let tokens = Tokens::match_parens(self.0, &mut errors);
+        let mut parser = Parser::new(&tokens, errors);
+        let expr = parser.group(&["operator", "end of expression"]);
+        if parser.errors.is_empty() {
+            Ok(expr)
+        } else {
+            Err(parser.errors)
+        }
+    }
+
+    /// Reports unmatched parens and leaves them out, and closes the ones that are never closed at
+    /// the end of the expression. Inserted parens around the whole expression are left as is
+    fn match_parens(
+        mut tokens: Vec<IndexedToken>,
+        errors: &mut Vec<ParseError>,
+    ) -> Vec<IndexedToken> {
+        use ParseErrorKind::UnmatchedParen;
+        use Token::*;
+
+        let end = tokens.pop().unwrap_or_else(|| IndexedToken::end(0));
+        let mut matched = Vec::with_capacity(tokens.len() + 1);
+        let mut parens = Vec::new();
+        for it in tokens {
+            match it.token {
+                Begin => parens.push(it),
+                End if parens.len() > 1 => {
+                    parens.pop();
                 }
-                // Values
-                (Val(_), Val(v)) => {
-                    normalized.push(NormToken::Op(Mul));
-                    normalized.push(NormToken::Val(v));
+                End => {
+                    errors.push(ParseError::at(
+                        UnmatchedParen,
+                        it,
+                        "')' has no matching '('",
+                    ));
+                    continue;
                 }
-                (Val(_), Op(o)) => normalized.push(NormToken::Op(o)),
-                (Val(_), End) => return Ok((Normalized(normalized), &tokens[1..])),
-                // Operators
-                (Op(_), Val(v)) => normalized.push(NormToken::Val(v)),
-                (Op(_), End) => Err(ParseError::IllegalExpression { index: left.index })?,
-                // left can't be End
-                (End, _) => unreachable!(),
+                _ => {}
             }
-            left = right;
-            tokens = &tokens[1..];
+            matched.push(it);
+        }
+        for it in parens.into_iter().skip(1) {
+            let err = ParseError::at(UnmatchedParen, it, "'(' is never closed");
+            errors.push(err.expecting(&["')'"]));
+            matched.push(IndexedToken::end(end.index));
         }
+        matched.push(end);
+        matched
     }
 }
 
-#[derive(Debug, Clone)]
-pub(super) struct Normalized(Vec<NormToken>);
+/// Associativity of an infix operator
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+}
 
-#[derive(Debug, Clone)]
-enum NormToken {
-    Op(Op),
-    Val(Val),
-    Expr(Normalized),
+/// Operator table: priority and associativity of every infix operator, or `None` for the tokens
+/// that aren't one. Operators of higher priority bind stronger, and the conditional expression
+/// `cond ? then : otherwise` binds the weakest
+fn infix(token: Token) -> Option<(u8, Assoc)> {
+    match token {
+        Token::Ternary(Ternary::Then) => Some((0, Assoc::Right)),
+        Token::Op(Op::Cmp(_)) => Some((1, Assoc::Left)),
+        Token::Op(Op::Add) | Token::Op(Op::Sub) => Some((2, Assoc::Left)),
+        Token::Op(Op::Mul) | Token::Op(Op::Div(_)) => Some((3, Assoc::Left)),
+        // Value right after another one multiplies it, `2(d6)` or `d6d8`
+        Token::Val(_) | Token::Begin | Token::Func(_) | Token::Attack => Some((3, Assoc::Left)),
+        Token::Ternary(Ternary::Else) | Token::End | Token::Sep(_) => None,
+    }
 }
 
-impl Normalized {
-    pub(super) fn to_expr(self) -> Expr {
-        Normalized::to_expr_recursive(ParsedExpr::None, self.0.as_slice())
+/// Builds the expression out of the tokens by precedence climbing.
+///
+/// Errors are added to `errors`, and the rest of the expression is checked as if the token in
+/// error was left out, or a value was in its place
+struct Parser<'a> {
+    tokens: &'a [IndexedToken],
+    /// Index of the next token
+    next: usize,
+    /// Token that was taken last, which decides what the next one may be
+    previous: IndexedToken,
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(tokens: &'a [IndexedToken], errors: Vec<ParseError>) -> Self {
+        Parser {
+            tokens,
+            next: 0,
+            previous: IndexedToken::begin(0),
+            errors,
+        }
+    }
+
+    /// Next token, or the closing paren once the tokens run out
+    fn peek(&self) -> IndexedToken {
+        match self.tokens.get(self.next) {
+            Some(token) => *token,
+            None => IndexedToken::end(self.tokens.last().map_or(0, |token| token.end)),
+        }
     }
 
-    fn to_expr_recursive(e: ParsedExpr, tokens: &[NormToken]) -> Expr {
-        let get_expr = |token: &NormToken| match token {
-            NormToken::Val(v) => Expr::Value(*v),
-            NormToken::Expr(e) => Normalized::to_expr_recursive(ParsedExpr::None, &e.0),
-            NormToken::Op(_) => unreachable!(),
-        };
-        let prio = |op: Op| match op {
-            Op::Add => 1,
-            Op::Sub => 1,
-            Op::Mul => 2,
-        };
+    fn advance(&mut self) -> IndexedToken {
+        let token = self.peek();
+        self.next += 1;
+        self.previous = token;
+        token
+    }
+
+    /// Stands in for a value that is missing or in error
+    fn invalid() -> Expr {
+        Expr::Value(Val::Num(1))
+    }
+
+    fn illegal(&mut self, token: IndexedToken, message: &str, expected: &[&'static str]) {
+        let kind = ParseErrorKind::IllegalExpression;
+        let error = ParseError::at(kind, token, message).expecting(expected);
+        self.errors.push(error);
+    }
+
+    /// Expression inside of the parens, starting with the opening one and up to the closing one.
+    /// Separators are only allowed in the attack, so anything after them is `expected` instead,
+    /// but it is still checked
+    fn group(&mut self, expected: &[&'static str]) -> Expr {
+        self.advance();
+        let expr = self.expression();
+        loop {
+            let token = self.advance();
+            if token.token == Token::End {
+                return expr;
+            }
+            self.errors.push(token.unexpected().expecting(expected));
+            self.expression();
+        }
+    }
 
-        match e {
-            ParsedExpr::None => {
-                let e = get_expr(&tokens[0]);
-                Normalized::to_expr_recursive(ParsedExpr::Expr(e), &tokens[1..])
+    /// Expression up to the closing paren or a separator. A `:` without its `?` is reported, and
+    /// the expression after it is checked too
+    fn expression(&mut self) -> Expr {
+        let left = self.atom();
+        let expr = self.climb(left, 0);
+        while let Token::Ternary(Ternary::Else) = self.peek().token {
+            let token = self.advance();
+            self.illegal(token, "':' without '?'", &["operator", "'?'"]);
+            let right = self.atom();
+            self.climb(right, 0);
+        }
+        expr
+    }
+
+    /// Builds the expression out of the operators with priority of at least `min_prio`, with
+    /// `left` on the left of the first one, and leaves the rest of the tokens to the caller
+    fn climb(&mut self, mut left: Expr, min_prio: u8) -> Expr {
+        while let Some((prio, assoc)) = infix(self.peek().token) {
+            if prio < min_prio {
+                break;
             }
-            ParsedExpr::Expr(e) => match tokens.get(0) {
-                None => e,
-                Some(NormToken::Op(op)) => Normalized::to_expr_recursive(
-                    ParsedExpr::Half { op: *op, left: e },
-                    &tokens[1..],
-                ),
-                Some(NormToken::Expr(_)) | Some(NormToken::Val(_)) => unreachable!(),
-            },
-            ParsedExpr::Half { op, left } => {
-                let next_op = tokens.iter().find_map(|t| match t {
-                    NormToken::Op(o) => Some(o),
-                    _ => None,
-                });
-                if next_op.map(|next_op| prio(op) < prio(*next_op)) == Some(true) {
-                    let right = Normalized::to_expr_recursive(ParsedExpr::None, &tokens);
+            // Right side of a left-associative operator only takes the ones that bind stronger
+            let next_prio = match assoc {
+                Assoc::Left => prio + 1,
+                Assoc::Right => prio,
+            };
+            let token = self.peek();
+            left = match token.token {
+                Token::Ternary(Ternary::Then) => {
+                    self.advance();
+                    // `then` is delimited by the `:`, so it can be any expression
+                    let then = self.atom();
+                    let then = self.climb(then, 0);
+                    let otherwise = if let Token::Ternary(Ternary::Else) = self.peek().token {
+                        self.advance();
+                        let otherwise = self.atom();
+                        self.climb(otherwise, next_prio)
+                    } else {
+                        self.illegal(token, "'?' without ':'", &["':'"]);
+                        Parser::invalid()
+                    };
+                    Expr::If {
+                        cond: Box::new(left),
+                        then: Box::new(then),
+                        otherwise: Box::new(otherwise),
+                    }
+                }
+                Token::Op(op) => {
+                    self.advance();
+                    let right = self.peek();
+                    if let (Op::Div(_), Token::Val(Val::Num(0))) = (op, right.token) {
+                        let kind = ParseErrorKind::DivisionByZero;
+                        self.errors
+                            .push(ParseError::at(kind, right, "division by zero"));
+                    }
+                    let right = self.atom();
                     Expr::Expr {
                         op,
                         left: Box::new(left),
-                        right: Box::new(right),
+                        right: Box::new(self.climb(right, next_prio)),
                     }
-                } else {
-                    let right = get_expr(&tokens[0]);
-                    let expr = Expr::Expr {
-                        op,
+                }
+                // Implicit multiplication, the value is the right side
+                _ => {
+                    let right = self.atom();
+                    Expr::Expr {
+                        op: Op::Mul,
                         left: Box::new(left),
-                        right: Box::new(right),
+                        right: Box::new(self.climb(right, next_prio)),
+                    }
+                }
+            };
+        }
+        left
+    }
+
+    /// Value that starts at the next token: a number or a die, everything inside of the parens,
+    /// or a call. Anything else is reported and replaced with an invalid value, and is left to
+    /// the caller
+    fn atom(&mut self) -> Expr {
+        use Token::*;
+
+        let previous = self.previous;
+        let token = self.peek();
+        match token.token {
+            Val(super::Val::Num(count)) => {
+                self.advance();
+                // Number of dice binds stronger than any operator, `10 / 2d6` divides by the
+                // whole roll
+                if let Val(die @ super::Val::Die(_)) = self.peek().token {
+                    self.advance();
+                    return Expr::Expr {
+                        op: super::Op::Mul,
+                        left: Box::new(Expr::Value(super::Val::Num(count))),
+                        right: Box::new(Expr::Value(die)),
                     };
-                    Normalized::to_expr_recursive(ParsedExpr::Expr(expr), &tokens[1..])
                 }
+                Expr::Value(super::Val::Num(count))
+            }
+            Val(v) => {
+                self.advance();
+                Expr::Value(v)
+            }
+            Begin => self.group(&["operator", "')'"]),
+            // Functions must be called, otherwise they are checked like values
+            Func(_) | Attack => {
+                self.advance();
+                let next = self.peek();
+                match (token.token, next.token) {
+                    (Func(func), Begin) => Expr::Call {
+                        func,
+                        arg: Box::new(self.group(&["operator", "')'"])),
+                    },
+                    (Attack, Begin) => self.attack(),
+                    _ => {
+                        self.errors.push(next.unexpected().expecting(&["'('"]));
+                        Parser::invalid()
+                    }
+                }
+            }
+            // Sign at the start is subtracted from zero
+            Op(super::Op::Add) | Op(super::Op::Sub) if matches!(previous.token, Begin | Sep(_)) => {
+                Expr::Value(super::Val::Num(0))
+            }
+            Op(_) if matches!(previous.token, Op(_)) => {
+                self.errors
+                    .push(token.unexpected().expecting(VALUE).suggest(
+                        "remove the extra operator",
+                        token.index,
+                        token.end,
+                        "",
+                    ));
+                Parser::invalid()
+            }
+            Op(_) | Ternary(_) => {
+                self.errors.push(token.unexpected().expecting(VALUE));
+                Parser::invalid()
+            }
+            End | Sep(_) => {
+                match previous.token {
+                    Op(_) => {
+                        let kind = ParseErrorKind::IllegalExpression;
+                        self.errors.push(
+                            ParseError::at(kind, previous, "operator has no right side")
+                                .expecting(VALUE)
+                                .suggest("remove the operator", previous.index, previous.end, ""),
+                        );
+                    }
+                    Ternary(_) => {
+                        let message = format!("{} has no value after it", previous.describe());
+                        self.illegal(previous, &message, VALUE);
+                    }
+                    _ => {
+                        let kind = ParseErrorKind::EmptyExpression;
+                        self.errors.push(
+                            ParseError::new(kind, previous.index, token.end, "empty expression")
+                                .expecting(VALUE),
+                        );
+                    }
+                }
+                Parser::invalid()
             }
         }
     }
-}
 
-enum ParsedExpr {
-    None,
-    Expr(Expr),
-    Half { op: Op, left: Expr },
+    /// Arguments of `attack(roll vs ac, damage)`, with an optional crit range,
+    /// `attack(roll vs ac, damage, 19)`, starting with the opening paren and up to the closing
+    /// one.
+    ///
+    /// The roll has to start with the die that is checked for a natural 1 or a crit, and the crit
+    /// range defaults to its highest face. An attack in error is replaced with an invalid value
+    fn attack(&mut self) -> Expr {
+        use Token::*;
+
+        let separators = [
+            Sep(self::Sep::Vs),
+            Sep(self::Sep::Comma),
+            Sep(self::Sep::Comma),
+        ];
+        // Whatever may follow every argument
+        let expected: [&[&str]; 4] = [&["'vs'"], &["','"], &["','", "')'"], &["')'"]];
+        let count = self.errors.len();
+        let mut args = Vec::new();
+        self.advance();
+        loop {
+            let first = self.peek();
+            args.push((first, self.expression()));
+            let mut token = self.advance();
+            match (token.token, separators.get(args.len() - 1)) {
+                (End, _) if args.len() >= 3 => break,
+                (sep, Some(expected)) if sep == *expected => {}
+                _ => {
+                    let expected = expected[(args.len() - 1).min(3)];
+                    self.errors.push(token.unexpected().expecting(expected));
+                    // Arguments can't be told apart anymore, but they are still checked
+                    while token.token != End {
+                        self.expression();
+                        token = self.advance();
+                    }
+                    break;
+                }
+            }
+        }
+        if self.errors.len() > count {
+            return Parser::invalid();
+        }
+
+        let mut args = args.into_iter();
+        let ((first, roll), (_, ac), (_, damage)) = match (args.next(), args.next(), args.next()) {
+            (Some(roll), Some(ac), Some(damage)) => (roll, ac, damage),
+            _ => return Parser::invalid(),
+        };
+        let die = match first.token {
+            Val(super::Val::Die(die)) => die,
+            _ => {
+                self.illegal(first, "attack roll has to start with a die", &["die"]);
+                return Parser::invalid();
+            }
+        };
+        // Successes can count below 1, which would be taken for a natural roll
+        if die.success.is_some() {
+            self.illegal(first, "attack roll can't count successes", &["die"]);
+            return Parser::invalid();
+        }
+        // Natural 1 always misses, so it can't be a crit
+        let crit = match args.next() {
+            None => die.edges,
+            Some((_, Expr::Value(super::Val::Num(crit)))) if crit >= 2 && crit <= die.edges => crit,
+            Some((first, _)) => {
+                let message = format!("crit has to be a number from 2 to {}", die.edges);
+                self.illegal(first, &message, &["number"]);
+                return Parser::invalid();
+            }
+        };
+        Expr::Attack {
+            roll: Box::new(roll),
+            natural: die,
+            ac: Box::new(ac),
+            damage: Box::new(damage),
+            crit,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use wasm_bindgen_test::*;
 
+    use super::super::limits::Budget;
+    use super::super::Hand;
     use super::*;
+    use ParseErrorKind::*;
+
+    /// Tokens without their ends, which are only used by errors
+    fn starts(tokens: &[IndexedToken]) -> Vec<(usize, Token)> {
+        tokens.iter().map(|t| (t.index, t.token)).collect()
+    }
+
+    /// The only error of `res`
+    fn error_at<T>(res: Result<T, Vec<ParseError>>) -> Result<(), (ParseErrorKind, usize)> {
+        res.map(|_| ()).map_err(|e| {
+            assert_eq!(e.len(), 1, "{:?}", e);
+            (e[0].kind, e[0].start)
+        })
+    }
+
+    /// Value of `expr` thrown through every step of the parser
+    fn throw(expr: &str) -> i64 {
+        let expr = Tokens::from_str(expr).unwrap().into_expr().unwrap();
+        let limits = Limits::default();
+        expr.throw(&mut rand::thread_rng(), &mut Budget::new(&limits))
+            .unwrap()
+            .value()
+    }
 
     #[test]
     #[wasm_bindgen_test]
@@ -273,8 +1107,8 @@ mod test {
         let expr = "d20 - 3 + 2(d6 * 2)";
         let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
         assert_eq!(
-            tokens.0,
-            vec![
+            starts(&tokens.0),
+            starts(&[
                 IndexedToken::begin(0),
                 IndexedToken::value(0, Val::Die(Die::new(20))),
                 IndexedToken::operation(4, Op::Sub),
@@ -287,7 +1121,7 @@ mod test {
                 IndexedToken::value(17, Val::Num(2)),
                 IndexedToken::end(18),
                 IndexedToken::end(18),
-            ]
+            ])
         );
     }
 
@@ -296,13 +1130,7 @@ mod test {
     fn tokenize_expr_unexpected_token() {
         let expr = "d20 * 200%";
         let tokens = Tokens::from_str(expr);
-        assert_eq!(
-            tokens,
-            Err(ParseError::UnexpectedToken {
-                index: 9,
-                token: '%'
-            })
-        )
+        assert_eq!(error_at(tokens), Err((UnexpectedToken { token: '%' }, 9)))
     }
 
     #[test]
@@ -310,6 +1138,504 @@ mod test {
     fn tokenize_expr_bad_die() {
         let expr = "d20 + d0 * 3";
         let tokens = Tokens::from_str(expr);
-        assert_eq!(tokens, Err(ParseError::BadDie { index: 6 }))
+        assert_eq!(error_at(tokens), Err((BadDie, 6)))
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_keep_drop() {
+        let expr = "4d6kh3 + d20kl + 5d8dh2";
+        let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
+        let die = |edges, rolls, drop_lowest, drop_highest| {
+            Val::Die(Die {
+                rolls,
+                drop_lowest,
+                drop_highest,
+                ..Die::new(edges)
+            })
+        };
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::value(0, die(6, 4, 1, 0)),
+                IndexedToken::operation(7, Op::Add),
+                IndexedToken::value(9, die(20, 1, 0, 0)),
+                IndexedToken::operation(15, Op::Add),
+                IndexedToken::value(17, die(8, 5, 0, 2)),
+                IndexedToken::end(17),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_bad_keep() {
+        assert_eq!(error_at(Tokens::from_str("1 + 2d20kh3")), Err((BadDie, 4)));
+        assert_eq!(error_at(Tokens::from_str("4d6dl4")), Err((BadDie, 0)));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_advantage() {
+        let expr = "adv(d20) + dis(d4) d20a d6d d6d8";
+        let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
+        let adv = KeepDrop::KeepHighest(1).apply(Die::new(20), 2).unwrap();
+        let dis = KeepDrop::KeepLowest(1).apply(Die::new(6), 2).unwrap();
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::function(0, Func::Adv),
+                IndexedToken::begin(3),
+                IndexedToken::value(4, Val::Die(Die::new(20))),
+                IndexedToken::end(7),
+                IndexedToken::operation(9, Op::Add),
+                IndexedToken::function(11, Func::Dis),
+                IndexedToken::begin(14),
+                IndexedToken::value(15, Val::Die(Die::new(4))),
+                IndexedToken::end(17),
+                IndexedToken::value(19, Val::Die(adv)),
+                IndexedToken::value(24, Val::Die(dis)),
+                IndexedToken::value(28, Val::Die(Die::new(6))),
+                IndexedToken::value(30, Val::Die(Die::new(8))),
+                IndexedToken::end(30),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn normalize_uncalled_function() {
+        let tokens = Tokens::from_str("2 + adv d20").unwrap();
+        assert_eq!(error_at(tokens.into_expr()), Err((IllegalExpression, 8)));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_exploding() {
+        let expr = "d6! + d10!!>=8 + 4d6!>4kh3";
+        let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
+        let explode = |edges, compound, threshold| Die {
+            explode: Some(Explode {
+                compound,
+                threshold,
+            }),
+            ..Die::new(edges)
+        };
+        let keep = KeepDrop::KeepHighest(3)
+            .apply(explode(6, false, 5), 4)
+            .unwrap();
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::value(0, Val::Die(explode(6, false, 6))),
+                IndexedToken::operation(4, Op::Add),
+                IndexedToken::value(6, Val::Die(explode(10, true, 8))),
+                IndexedToken::operation(15, Op::Add),
+                IndexedToken::value(17, Val::Die(keep)),
+                IndexedToken::end(17),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_bad_explode() {
+        assert_eq!(error_at(Tokens::from_str("d1!")), Err((BadDie, 0)));
+        assert_eq!(error_at(Tokens::from_str("d6!>6")), Err((BadDie, 0)));
+        assert_eq!(error_at(Tokens::from_str("d6!>=")), Err((BadDie, 0)));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_reroll() {
+        let expr = "2d6r<3 + d20rr1";
+        let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
+        let reroll = |edges, once, cmp, value| Die {
+            reroll: Some(Reroll {
+                once,
+                cond: Cond { cmp, value },
+            }),
+            ..Die::new(edges)
+        };
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::value(0, Val::Num(2)),
+                IndexedToken::value(1, Val::Die(reroll(6, true, Cmp::Lt, 3))),
+                IndexedToken::operation(7, Op::Add),
+                IndexedToken::value(9, Val::Die(reroll(20, false, Cmp::Eq, 1))),
+                IndexedToken::end(9),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_bad_reroll() {
+        assert_eq!(error_at(Tokens::from_str("d6r")), Err((BadDie, 0)));
+        assert_eq!(error_at(Tokens::from_str("d6r>6")), Err((BadDie, 0)));
+        assert_eq!(error_at(Tokens::from_str("d6rr<=6")), Err((BadDie, 0)));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_success() {
+        // Comparison after `!` is the explosion trigger, and after `s` the success
+        let expr = "8d10s>=7 6d6!>5s>=5f1";
+        let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
+        let cond = |cmp, value| Cond { cmp, value };
+        let pool = Die {
+            success: Some(Success {
+                success: cond(Cmp::Ge, 7),
+                failure: None,
+            }),
+            ..Die::new(10)
+        };
+        let botch = Die {
+            explode: Some(Explode {
+                compound: false,
+                threshold: 6,
+            }),
+            success: Some(Success {
+                success: cond(Cmp::Ge, 5),
+                failure: Some(cond(Cmp::Eq, 1)),
+            }),
+            ..Die::new(6)
+        };
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::value(0, Val::Num(8)),
+                IndexedToken::value(1, Val::Die(pool)),
+                IndexedToken::value(9, Val::Num(6)),
+                IndexedToken::value(10, Val::Die(botch)),
+                IndexedToken::end(10),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_bad_success() {
+        assert_eq!(error_at(Tokens::from_str("d10s")), Err((BadDie, 0)));
+        assert_eq!(error_at(Tokens::from_str("d10s>=")), Err((BadDie, 0)));
+        assert_eq!(error_at(Tokens::from_str("4d6kh3s>=5")), Err((BadDie, 0)));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_div() {
+        let expr = "8d6/2 /^3/~ 4";
+        let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::value(0, Val::Num(8)),
+                IndexedToken::value(1, Val::Die(Die::new(6))),
+                IndexedToken::operation(3, Op::Div(Rounding::Floor)),
+                IndexedToken::value(4, Val::Num(2)),
+                IndexedToken::operation(6, Op::Div(Rounding::Ceil)),
+                IndexedToken::value(8, Val::Num(3)),
+                IndexedToken::operation(9, Op::Div(Rounding::Round)),
+                IndexedToken::value(12, Val::Num(4)),
+                IndexedToken::end(12),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn normalize_division_by_zero() {
+        let tokens = Tokens::from_str("d20 / 0").unwrap();
+        assert_eq!(error_at(tokens.into_expr()), Err((DivisionByZero, 6)));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_expr_cmp() {
+        let expr = "d20+7 >= 15 < 1<=2>3 == 0";
+        let tokens = Tokens::from_str(expr).expect("Unable to tokenize valid expr");
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::value(0, Val::Die(Die::new(20))),
+                IndexedToken::operation(3, Op::Add),
+                IndexedToken::value(4, Val::Num(7)),
+                IndexedToken::operation(6, Op::Cmp(Cmp::Ge)),
+                IndexedToken::value(9, Val::Num(15)),
+                IndexedToken::operation(12, Op::Cmp(Cmp::Lt)),
+                IndexedToken::value(14, Val::Num(1)),
+                IndexedToken::operation(15, Op::Cmp(Cmp::Le)),
+                IndexedToken::value(17, Val::Num(2)),
+                IndexedToken::operation(18, Op::Cmp(Cmp::Gt)),
+                IndexedToken::value(19, Val::Num(3)),
+                IndexedToken::operation(21, Op::Cmp(Cmp::Eq)),
+                IndexedToken::value(24, Val::Num(0)),
+                IndexedToken::end(24),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn to_expr_priority() {
+        assert_eq!(throw("2 - 3 * 1 + 4"), 3);
+        assert_eq!(throw("12 / 2 / 3 - 1"), 1);
+        assert_eq!(throw("1 + 2 * 3 >= 7 + 0"), 1);
+        assert_eq!(throw("10 - 2 * 3 + 1 > 5"), 0);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn to_expr_dice_count() {
+        let expr = |expr| parse(expr, &Limits::default()).unwrap();
+        assert_eq!(expr("20 / 2d6"), expr("20 / (2d6)"));
+        assert_eq!(expr("d20 / 2d6"), expr("d20 / (2d6)"));
+        assert_eq!(expr("3 * 2d6 - 1"), expr("3 * (2d6) - 1"));
+        let hand = Hand::from_str("20 / 2d6").unwrap();
+        for _ in 0..100 {
+            assert!((1..=10).contains(&hand.throw().unwrap()));
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn to_expr_ternary() {
+        assert_eq!(throw("1 + 1 >= 2 ? 2 * 3 : 4"), 6);
+        assert_eq!(throw("0 ? 1 : 0 ? 2 : 3"), 3);
+        assert_eq!(throw("1 ? 0 ? 1 : 2 : 3"), 2);
+        assert_eq!(throw("(1 ? 5 : 6) + 1"), 6);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn to_expr_malformed() {
+        let (begin, end) = (IndexedToken::begin(0), IndexedToken::end(1));
+        let (one, add) = (
+            IndexedToken::value(0, Val::Num(1)),
+            IndexedToken::operation(0, Op::Add),
+        );
+        let into_expr = |tokens: &[IndexedToken]| Tokens(tokens.to_vec()).into_expr().ok();
+        assert_eq!(into_expr(&[]), None);
+        assert_eq!(into_expr(&[end]), None);
+        assert_eq!(into_expr(&[begin]), None);
+        assert_eq!(into_expr(&[begin, one, add]), None);
+        assert_eq!(
+            into_expr(&[begin, one, end]),
+            Some(Expr::Value(Val::Num(1)))
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn normalize_bad_ternary() {
+        let normalize = |expr| error_at(Tokens::from_str(expr).unwrap().into_expr());
+        let illegal = |index| Err((IllegalExpression, index));
+        assert_eq!(normalize("1 ? 2"), illegal(2));
+        assert_eq!(normalize("1 : 2"), illegal(2));
+        assert_eq!(normalize("1 ? : 2"), illegal(4));
+        // Both parts are out of place
+        let errors = parse_errors("(1 ? 2) : 3");
+        let starts: Vec<_> = errors.iter().map(|e| e.start).collect();
+        assert_eq!(starts, vec![3, 8]);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn tokenize_attack() {
+        let tokens = Tokens::from_str("attack(d20 vs 15, 7, 19)").unwrap();
+        assert_eq!(
+            starts(&tokens.0),
+            starts(&[
+                IndexedToken::begin(0),
+                IndexedToken::attack(0),
+                IndexedToken::begin(6),
+                IndexedToken::value(7, Val::Die(Die::new(20))),
+                IndexedToken::separator(11, Sep::Vs),
+                IndexedToken::value(14, Val::Num(15)),
+                IndexedToken::separator(16, Sep::Comma),
+                IndexedToken::value(18, Val::Num(7)),
+                IndexedToken::separator(19, Sep::Comma),
+                IndexedToken::value(21, Val::Num(19)),
+                IndexedToken::end(23),
+                IndexedToken::end(23),
+            ])
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn normalize_bad_attack() {
+        let normalize = |expr| error_at(Tokens::from_str(expr).unwrap().into_expr());
+        let illegal = |index| Err((IllegalExpression, index));
+        assert_eq!(normalize("attack(d20 + 1 vs 10, d6)"), Ok(()));
+        assert_eq!(normalize("2 + attack(d20 vs 10, d6, 19)"), Ok(()));
+        assert_eq!(normalize("attack(d20, d6)"), illegal(10));
+        assert_eq!(normalize("attack(d20 vs 10)"), illegal(16));
+        assert_eq!(normalize("attack(d20 vs 10, d6, 19, 1)"), illegal(24));
+        assert_eq!(normalize("attack(d20 vs 10 vs 9, d6)"), illegal(17));
+        assert_eq!(normalize("attack(1 + d20 vs 10, d6)"), illegal(7));
+        assert_eq!(normalize("attack(d6s>=5f1 vs 0, 1)"), illegal(7));
+        assert_eq!(normalize("attack(d20 vs 10, d6, 1)"), illegal(22));
+        assert_eq!(normalize("attack(d20 vs 10, d6, 21)"), illegal(22));
+        assert_eq!(normalize("attack(d20 vs 10, d6, d4)"), illegal(22));
+        assert_eq!(normalize("attack(d20 vs , d6)"), Err((EmptyExpression, 11)));
+        assert_eq!(normalize("attack d20"), illegal(7));
+        assert_eq!(normalize("d20 vs 10"), illegal(4));
+        assert_eq!(normalize("(d20, 10)"), illegal(4));
+    }
+
+    fn parse_errors(expr: &str) -> Vec<ParseError> {
+        Hand::from_str(expr).map(|_| ()).unwrap_err()
+    }
+
+    fn parse_error(expr: &str) -> ParseError {
+        let mut errors = parse_errors(expr);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        errors.remove(0)
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn error_spans() {
+        let span = |expr| {
+            let e = parse_error(expr);
+            (e.start, e.end)
+        };
+        assert_eq!(span("d20 + d0 * 3"), (6, 8));
+        assert_eq!(span("1 + 4d6dl5 + 2"), (4, 10));
+        assert_eq!(span("1 + attack d20"), (11, 14));
+        assert_eq!(span("1) + 2"), (1, 2));
+        assert_eq!(span("(1 + (2)"), (0, 1));
+        assert_eq!(span("1 + ()"), (4, 6));
+        assert_eq!(span(""), (0, 0));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn error_messages() {
+        let e = parse_error("d20 +");
+        assert_eq!(e.expected, vec!["number", "die", "'('"]);
+        assert_eq!(
+            e.message,
+            "operator has no right side, expected number, die or '('"
+        );
+        let e = parse_error("attack(d20 vs 10 d6)");
+        assert_eq!(e.expected, vec!["','"]);
+        assert_eq!(e.message, "unexpected ')', expected ','");
+        let e = parse_error("1 ? 2");
+        assert_eq!(e.message, "'?' without ':', expected ':'");
+        assert_eq!((e.start, e.end), (2, 3));
+        assert_eq!(parse_error("d6 / 0").message, "division by zero");
+        assert_eq!(parse_error("2d6 % 2").message, "unexpected '%'");
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn error_suggestions() {
+        let suggestion = |expr| {
+            let s = parse_error(expr).suggestion.unwrap();
+            (s.start, s.end, s.replacement)
+        };
+        assert_eq!(suggestion("d + 1"), (0, 1, "d20".to_string()));
+        assert_eq!(suggestion("2dd6"), (1, 2, String::new()));
+        assert_eq!(suggestion("d20 + "), (4, 5, String::new()));
+        assert_eq!(suggestion("2 + * 3"), (4, 5, String::new()));
+        assert_eq!(suggestion("2 x 3"), (2, 3, "*".to_string()));
+        assert_eq!(suggestion("d20 = 3"), (4, 5, "==".to_string()));
+        assert_eq!(parse_error("(1 + 2").suggestion, None);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn error_json() {
+        let json = serde_json::to_string(&parse_error("2x3")).unwrap();
+        assert_eq!(
+            json,
+            r#"{"error":"unexpected_token","token":"x","start":1,"end":2,"expected":[],"message":"unexpected 'x'","suggestion":{"message":"multiply with '*'","start":1,"end":2,"replacement":"*"}}"#
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn limit_errors() {
+        let exceeded = |limit, value, max| LimitExceeded { limit, value, max };
+        let error = parse_error("1001d6 + 1");
+        assert_eq!(error.kind, exceeded(Limit::Dice, 1001, 1000));
+        assert_eq!((error.start, error.end), (0, 6));
+        assert_eq!(error.message, "at most 1000 dice are allowed");
+        let errors = parse_errors("2 + 2000d20000");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].kind, exceeded(Limit::Faces, 20000, 10000));
+
+        let limits = Limits {
+            max_dice: 4,
+            max_faces: 20,
+            ..Limits::default()
+        };
+        assert!(Hand::parse_with("4d20", &limits).is_ok());
+        let errors = Hand::parse_with("5d6 + d100", &limits).unwrap_err();
+        let kinds = errors.iter().map(|e| e.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![exceeded(Limit::Dice, 5, 4), exceeded(Limit::Faces, 100, 20)]
+        );
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn recover_errors() {
+        let errors = |expr| {
+            let errors = parse_errors(expr);
+            errors.iter().map(|e| (e.kind, e.start)).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            errors("2 x d0 + * 3 / 0"),
+            vec![
+                (UnexpectedToken { token: 'x' }, 2),
+                (BadDie, 4),
+                (IllegalExpression, 9),
+                (DivisionByZero, 15),
+            ]
+        );
+        assert_eq!(
+            errors("(1 + ) * (2 ? 3"),
+            vec![
+                (IllegalExpression, 3),
+                (UnmatchedParen, 9),
+                (IllegalExpression, 12),
+            ]
+        );
+        assert_eq!(
+            errors("1) + adv d20 ? 2"),
+            vec![
+                (UnmatchedParen, 1),
+                (IllegalExpression, 9),
+                (IllegalExpression, 13),
+            ]
+        );
+        assert_eq!(
+            errors("attack(d20 vs 10) + attack(2 vs 10, d6) + (1, 2)"),
+            vec![
+                (IllegalExpression, 16),
+                (IllegalExpression, 27),
+                (IllegalExpression, 44),
+            ]
+        );
+        assert_eq!(
+            errors("d6r>6 + % + 1 +"),
+            vec![
+                (BadDie, 0),
+                (UnexpectedToken { token: '%' }, 8),
+                (IllegalExpression, 14),
+            ]
+        );
     }
 }
@@ -0,0 +1,210 @@
+use super::{Freq, FreqGraph};
+
+/// Summary of a distribution. Truncated outcomes are unknown, so they are left out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    pub mean: f64,
+    pub variance: f64,
+    pub stddev: f64,
+    /// Lowest outcome that is at least as high as half of the outcomes
+    pub median: i64,
+    /// Most frequent outcomes, in ascending order
+    pub modes: Vec<i64>,
+    pub min: i64,
+    pub max: i64,
+}
+
+impl<F: Freq> FreqGraph<F> {
+    /// `None` if there are no outcomes besides the truncated ones
+    pub fn statistics(&self) -> Option<Statistics> {
+        let min = self.outcomes().next()?.0;
+        let max = self.outcomes().last()?.0;
+        let kept = self.total() - &self.truncated;
+        let chances = || self.outcomes().map(|(v, f)| (v as f64, f.ratio(&kept)));
+
+        let mean = chances().map(|(v, p)| v * p).sum::<f64>();
+        let variance = chances().map(|(v, p)| (v - mean).powi(2) * p).sum::<f64>();
+        let top = self
+            .outcomes()
+            .map(|(_, f)| f)
+            .fold(&self.values[0], |acc, f| if f > acc { f } else { acc });
+        let modes = self
+            .outcomes()
+            .filter(|(_, f)| *f == top)
+            .map(|(v, _)| v)
+            .collect();
+
+        Some(Statistics {
+            mean,
+            variance,
+            stddev: variance.sqrt(),
+            median: self.percentile(50f64)?,
+            modes,
+            min,
+            max,
+        })
+    }
+
+    /// Lowest outcome that is at least as high as `percent` of the outcomes, clamped to `0..=100`.
+    /// Truncated outcomes are left out, `None` if there are no other outcomes
+    pub fn percentile(&self, percent: f64) -> Option<i64> {
+        let kept = self.total() - &self.truncated;
+        let fraction = percent.clamp(0f64, 100f64) / 100f64;
+        let mut below = F::zero();
+        for (v, f) in self.outcomes() {
+            below += f;
+            if below.ratio(&kept) >= fraction {
+                return Some(v);
+            }
+        }
+        // Rounding may keep the sum of the chances just short of 100%
+        self.outcomes().last().map(|(v, _)| v)
+    }
+
+    /// Cumulative distribution, frequency of the outcome being at most each value, `P(X <= k)`.
+    /// Truncated outcomes stay apart in `truncated`
+    pub fn cdf(&self) -> Self {
+        let mut sum = F::zero();
+        let values = self
+            .values
+            .iter()
+            .map(|f| {
+                sum += f;
+                sum.clone()
+            })
+            .collect();
+        Self {
+            offset: self.offset,
+            values,
+            truncated: self.truncated.clone(),
+        }
+    }
+
+    /// Survival function, frequency of the outcome being at least each value, `P(X >= k)`.
+    /// Truncated outcomes stay apart in `truncated`
+    pub fn survival(&self) -> Self {
+        let mut sum = F::zero();
+        let mut values: Vec<F> = self
+            .values
+            .iter()
+            .rev()
+            .map(|f| {
+                sum += f;
+                sum.clone()
+            })
+            .collect();
+        values.reverse();
+        Self {
+            offset: self.offset,
+            values,
+            truncated: self.truncated.clone(),
+        }
+    }
+
+    /// Outcomes with non-zero frequencies, in ascending order
+    fn outcomes(&self) -> impl Iterator<Item = (i64, &F)> {
+        let offset = self.offset;
+        self.values
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !f.is_zero())
+            .map(move |(n, f)| (n as i64 + offset, f))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use wasm_bindgen_test::*;
+
+    use super::super::Hand;
+    use super::*;
+
+    fn statistics(expr: &str) -> Statistics {
+        Hand::from_str(expr)
+            .unwrap()
+            .analyze()
+            .unwrap()
+            .statistics()
+            .unwrap()
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn statistics_die() {
+        let stats = statistics("d6");
+        assert_eq!(stats.mean, 3.5);
+        assert!((stats.variance - 35f64 / 12f64).abs() < 1e-12);
+        assert!((stats.stddev - (35f64 / 12f64).sqrt()).abs() < 1e-12);
+        assert_eq!(stats.median, 3);
+        assert_eq!(stats.modes, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!((stats.min, stats.max), (1, 6));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn statistics_sum() {
+        let stats = statistics("2d6 - 10");
+        assert!((stats.mean + 3f64).abs() < 1e-12);
+        assert_eq!(stats.median, -3);
+        assert_eq!(stats.modes, vec![-3]);
+        assert_eq!((stats.min, stats.max), (-8, 2));
+        // Zero frequencies are not outcomes
+        let stats = statistics("d4 * 2");
+        assert_eq!((stats.min, stats.max), (2, 8));
+        assert_eq!(stats.modes, vec![2, 4, 6, 8]);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn percentile() {
+        let graph = Hand::from_str("2d6").unwrap().analyze().unwrap();
+        assert_eq!(graph.percentile(0f64), Some(2));
+        assert_eq!(graph.percentile(25f64), Some(5));
+        assert_eq!(graph.percentile(50f64), Some(7));
+        assert_eq!(graph.percentile(100f64), Some(12));
+        assert_eq!(graph.percentile(1000f64), Some(12));
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn statistics_truncated() {
+        let graph = FreqGraph {
+            offset: 1,
+            values: vec![1f64, 0f64, 1f64],
+            truncated: 2f64,
+        };
+        let stats = graph.statistics().unwrap();
+        assert_eq!(stats.mean, 2f64);
+        assert_eq!(stats.median, 1);
+        let graph = FreqGraph {
+            offset: 0,
+            values: vec![0f64],
+            truncated: 1f64,
+        };
+        assert_eq!(graph.statistics(), None);
+        assert_eq!(graph.percentile(50f64), None);
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn cumulative() {
+        let graph = Hand::from_str("2d4").unwrap().analyze().unwrap();
+        let cdf = graph.cdf();
+        assert_eq!(cdf.offset, 2);
+        assert_eq!(
+            cdf.values,
+            vec![1f64, 3f64, 6f64, 10f64, 13f64, 15f64, 16f64]
+        );
+        let survival = graph.survival();
+        assert_eq!(survival.offset, 2);
+        assert_eq!(
+            survival.values,
+            vec![16f64, 15f64, 13f64, 10f64, 6f64, 3f64, 1f64]
+        );
+
+        let graph = Hand::from_str("d2!").unwrap().analyze().unwrap();
+        assert_eq!(graph.cdf().truncated, graph.truncated);
+        assert_eq!(graph.survival().values[0], graph.total() - graph.truncated);
+    }
+}
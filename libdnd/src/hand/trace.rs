@@ -0,0 +1,184 @@
+use serde_derive::Serialize;
+
+/// Record of a throw: every die that was rolled, and the value of every sub-expression
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Trace {
+    Num {
+        value: i64,
+    },
+    Dice {
+        value: i64,
+        dice: Vec<DieRoll>,
+    },
+    /// Dice thrown as many times as the `count` came out
+    Repeated {
+        value: i64,
+        count: Box<Trace>,
+        dice: Vec<Trace>,
+    },
+    Op {
+        value: i64,
+        op: &'static str,
+        left: Box<Trace>,
+        right: Box<Trace>,
+    },
+    /// Both throws of an advantage or a disadvantage
+    Call {
+        value: i64,
+        func: &'static str,
+        first: Box<Trace>,
+        second: Box<Trace>,
+    },
+    /// Only the branch that is taken is thrown
+    If {
+        value: i64,
+        cond: Box<Trace>,
+        branch: Box<Trace>,
+    },
+    /// Natural 1 and critical hits don't need the rest of the `roll`, and misses don't need the
+    /// `damage`
+    Attack {
+        value: i64,
+        natural: Box<Trace>,
+        roll: Option<Box<Trace>>,
+        ac: Box<Trace>,
+        outcome: Outcome,
+        damage: Option<Box<Trace>>,
+    },
+}
+
+impl Trace {
+    pub fn value(&self) -> i64 {
+        match self {
+            Trace::Num { value }
+            | Trace::Dice { value, .. }
+            | Trace::Repeated { value, .. }
+            | Trace::Op { value, .. }
+            | Trace::Call { value, .. }
+            | Trace::If { value, .. }
+            | Trace::Attack { value, .. } => *value,
+        }
+    }
+}
+
+/// A single die of a throw
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct DieRoll {
+    /// Faces that were kept, the first roll followed by every explosion
+    pub faces: Vec<i64>,
+    /// Faces that were rolled and rerolled away, in the order they came up
+    pub rerolled: Vec<i64>,
+    /// Sum of the faces, or the number of successes
+    pub value: i64,
+    /// Left out by keeping or dropping dice
+    pub dropped: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Miss,
+    Hit,
+    Critical,
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use std::str::FromStr;
+    use wasm_bindgen_test::*;
+
+    use super::super::Hand;
+    use super::*;
+
+    fn trace(expr: &str, seed: u64) -> Trace {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        Hand::from_str(expr).unwrap().trace_with(&mut rng).unwrap()
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn trace_keep_highest() {
+        for seed in 0..20 {
+            let (value, left, right) = match trace("4d6kh3 + 5", seed) {
+                Trace::Op {
+                    value, left, right, ..
+                } => (value, left, right),
+                t => panic!("{:?}", t),
+            };
+            assert_eq!(*right, Trace::Num { value: 5 });
+            let dice = match *left {
+                Trace::Dice { dice, .. } => dice,
+                t => panic!("{:?}", t),
+            };
+            assert_eq!(dice.len(), 4);
+            let dropped: Vec<_> = dice.iter().filter(|d| d.dropped).collect();
+            assert_eq!(dropped.len(), 1);
+            assert!(dice.iter().all(|d| d.value >= dropped[0].value));
+            let kept: i64 = dice.iter().filter(|d| !d.dropped).map(|d| d.value).sum();
+            assert_eq!(value, kept + 5);
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn trace_reroll_explode() {
+        for seed in 0..20 {
+            match trace("d6rr<6", seed) {
+                Trace::Dice { value, dice } => {
+                    assert_eq!(value, 6);
+                    assert_eq!(dice[0].faces, vec![6]);
+                    assert!(dice[0].rerolled.iter().all(|f| *f < 6));
+                }
+                t => panic!("{:?}", t),
+            }
+            match trace("d4!", seed) {
+                Trace::Dice { value, dice } => {
+                    let faces = &dice[0].faces;
+                    assert!(faces[..faces.len() - 1].iter().all(|f| *f == 4));
+                    assert!(faces[faces.len() - 1] < 4);
+                    assert_eq!(value, faces.iter().sum::<i64>());
+                }
+                t => panic!("{:?}", t),
+            }
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn trace_attack() {
+        for seed in 0..50 {
+            match trace("attack(d20 + 5 vs 15, (d2)d6)", seed) {
+                Trace::Attack {
+                    value,
+                    natural,
+                    roll,
+                    outcome,
+                    damage,
+                    ..
+                } => {
+                    match (natural.value(), outcome) {
+                        (1, Outcome::Miss) | (20, Outcome::Critical) => assert_eq!(roll, None),
+                        (_, Outcome::Hit) => assert!(roll.unwrap().value() >= 15),
+                        (_, Outcome::Miss) => assert!(roll.unwrap().value() < 15),
+                        t => panic!("{:?}", t),
+                    }
+                    assert_eq!(value, damage.map_or(0, |d| d.value()));
+                }
+                t => panic!("{:?}", t),
+            }
+        }
+    }
+
+    #[test]
+    #[wasm_bindgen_test]
+    fn trace_json() {
+        let json = serde_json::to_string(&trace("2 + d1", 0)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"kind":"op","value":3,"op":"+","left":{"kind":"num","value":2},"right":{"kind":"dice","value":1,"dice":[{"faces":[1],"rerolled":[],"value":1,"dropped":false}]}}"#
+        );
+    }
+}
@@ -2,10 +2,13 @@ use std::str::FromStr;
 use wasm_bindgen::prelude::*;
 
 mod dto;
-mod hand;
+pub mod hand;
 
-use dto::{AnalyzeResponse, CalculateResponse, Dice, Request, Response};
-use hand::{Hand, ParseError};
+use dto::{AnalyzeResponse, CalculateResponse, Dice, ErrorResponse, Request, Response};
+use hand::{AnalyzeOptions, Hand};
+use num_bigint::BigUint;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
 #[wasm_bindgen]
 extern "C" {
@@ -24,8 +27,10 @@ pub fn message_dispatcher(msg: &str) -> JsValue {
 
     let response = if let Ok(message) = serde_json::from_str(msg) {
         match message {
-            Request::CalculateDice(Dice { expression }) => calculate_dice(expression).into(),
-            Request::AnalyzeDice(Dice { expression }) => analyze_dice(expression).into(),
+            Request::CalculateDice(Dice {
+                expression, seed, ..
+            }) => calculate_dice(expression, seed).into(),
+            Request::AnalyzeDice(dice) => analyze_dice(dice).into(),
         }
     } else {
         Response::MessageParseError
@@ -33,15 +38,36 @@ pub fn message_dispatcher(msg: &str) -> JsValue {
     serde_json::to_string(&response).unwrap().into()
 }
 
-fn calculate_dice(expr: String) -> Result<CalculateResponse, ParseError> {
-    Hand::from_str(expr.as_str()).map(|h| CalculateResponse { result: h.throw() })
+/// Throws with a generator of a fixed algorithm, so a seed throws the same result in every
+/// version. Random seeds stay below 2^53 to survive the trip through JavaScript numbers
+fn calculate_dice(expr: String, seed: Option<u64>) -> Result<CalculateResponse, ErrorResponse> {
+    let hand = Hand::from_str(expr.as_str())?;
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen_range(0, 1 << 53));
+    let trace = hand.trace_with(&mut ChaCha8Rng::seed_from_u64(seed))?;
+    Ok(CalculateResponse {
+        result: trace.value(),
+        normalized_expression: hand.to_string(),
+        seed,
+        trace,
+    })
 }
 
 #[allow(unused)]
-fn analyze_dice(expr: String) -> Result<AnalyzeResponse, ParseError> {
-    Hand::from_str(expr.as_str()).map(|h| AnalyzeResponse {
-        result: h.analyze().into(),
-    })
+fn analyze_dice(dice: Dice) -> Result<AnalyzeResponse, ErrorResponse> {
+    let hand = Hand::from_str(dice.expression.as_str())?;
+    let options = match dice.explosion_depth {
+        Some(explosion_depth) => AnalyzeOptions { explosion_depth },
+        None => AnalyzeOptions::default(),
+    };
+    let (series, percentiles) = (dice.series, &dice.percentiles);
+    let response = if dice.exact {
+        let graph = hand.analyze_with::<BigUint>(&options)?;
+        AnalyzeResponse::new(&hand, graph, series, percentiles)
+    } else {
+        let graph = hand.analyze_with::<f64>(&options)?;
+        AnalyzeResponse::new(&hand, graph, series, percentiles)
+    };
+    Ok(response)
 }
 
 #[cfg(all(test, target_arch = "wasm32"))]
@@ -56,10 +82,38 @@ mod test {
         let json = r#"{"command":"calculate_dice","expression":"d20"}"#;
         let msg: Request =
             serde_json::from_str(json).expect("Unable to parse valid calculate_dice message");
-        if let Request::CalculateDice(Dice { expression }) = msg {
+        if let Request::CalculateDice(Dice { expression, .. }) = msg {
             assert_eq!(expression, String::from("d20"))
         } else {
             panic!("Invalid message type parsed")
         }
     }
+    #[wasm_bindgen_test]
+    fn calculate_dice_seeded() {
+        let first = calculate_dice("10d20".into(), Some(42)).unwrap();
+        let second = calculate_dice("10d20".into(), Some(42)).unwrap();
+        assert_eq!((first.result, first.seed), (second.result, 42));
+        let random = calculate_dice("10d20".into(), None).unwrap();
+        assert!(random.seed < 1 << 53);
+    }
+
+    #[wasm_bindgen_test]
+    fn calculate_dice_errors() {
+        let response: Response = calculate_dice("2 x d0".into(), None).into();
+        let json = serde_json::to_value(&response).unwrap();
+        let errors = json["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0]["error"], "unexpected_token");
+        assert_eq!(errors[1]["error"], "bad_die");
+    }
+
+    #[wasm_bindgen_test]
+    fn calculate_dice_limits() {
+        let response: Response = calculate_dice("(1001)d6".into(), None).into();
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            json["errors"],
+            serde_json::json!([{"error": "limit_exceeded", "limit": "dice", "value": 1001, "max": 1000}])
+        );
+    }
 }
@@ -0,0 +1,77 @@
+//! Chi-squared tests of thrown dice against their expected distributions. Throws are seeded, so
+//! the tests are deterministic, but any bias in sampling shows up as a failure
+#![cfg(not(target_arch = "wasm32"))]
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::str::FromStr;
+
+use libdnd::hand::Hand;
+
+/// Throws per expected outcome
+const THROWS_PER_OUTCOME: usize = 2_000;
+
+/// Chi-squared statistic of `observed` counts against `expected` chances
+fn chi_squared(observed: &[usize], expected: &[f64]) -> f64 {
+    let throws = observed.iter().sum::<usize>() as f64;
+    observed
+        .iter()
+        .zip(expected.iter())
+        .map(|(o, e)| (*o as f64 - e * throws).powi(2) / (e * throws))
+        .sum()
+}
+
+/// Critical value of chi-squared at about 1e-6 significance, by the Wilson–Hilferty
+/// approximation
+fn critical(df: usize) -> f64 {
+    const Z: f64 = 4.75;
+    let df = df as f64;
+    let k = 2f64 / (9f64 * df);
+    df * (1f64 - k + Z * k.sqrt()).powi(3)
+}
+
+/// Throws `expr` and checks the counts of `first..first + expected.len()` against `expected`
+fn assert_fair(expr: &str, seed: u64, first: i64, expected: &[f64]) {
+    let hand = Hand::from_str(expr).unwrap();
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut observed = vec![0usize; expected.len()];
+    for _ in 0..THROWS_PER_OUTCOME * expected.len() {
+        let res = hand.throw_with(&mut rng).unwrap();
+        assert!(
+            (first..first + expected.len() as i64).contains(&res),
+            "{} threw {}",
+            expr,
+            res
+        );
+        observed[(res - first) as usize] += 1;
+    }
+    let stat = chi_squared(&observed, expected);
+    let critical = critical(expected.len() - 1);
+    assert!(stat < critical, "{}: {} >= {}", expr, stat, critical);
+}
+
+#[test]
+fn uniform_dice() {
+    for edges in [2usize, 3, 4, 6, 7, 8, 10, 12, 20, 100].iter() {
+        let expected = vec![1f64 / *edges as f64; *edges];
+        assert_fair(&format!("d{}", edges), *edges as u64, 1, &expected);
+    }
+}
+
+#[test]
+fn rerolled_dice() {
+    // Rerolling ones until something else comes up is a uniform d5 shifted by one
+    assert_fair("d6rr1", 1, 2, &[0.2; 5]);
+    // A single reroll keeps a one with the chance of rolling it again
+    let mut expected = vec![7f64 / 36f64; 6];
+    expected[0] = 1f64 / 36f64;
+    assert_fair("d6r1", 2, 1, &expected);
+}
+
+#[test]
+fn sums_of_dice() {
+    let expected: Vec<f64> = (2..=12)
+        .map(|v: i64| (6 - (v - 7).abs()) as f64 / 36f64)
+        .collect();
+    assert_fair("2d6", 3, 2, &expected);
+}
@@ -0,0 +1,107 @@
+//! Fuzz tests of the whole pipeline: no expression, however broken, can panic the parser, and no
+//! expression that parses can panic the throw or the analysis, or run past the limits
+#![cfg(not(target_arch = "wasm32"))]
+
+use proptest::prelude::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use libdnd::hand::{AnalyzeOptions, Hand, Limits};
+
+/// Pieces of expressions, valid or not, including numbers that overflow on their own or in
+/// arithmetic
+fn piece() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => (0u32..10).prop_map(|n| n.to_string()),
+        4 => (0u32..7).prop_map(|d| format!("d{}", d)),
+        1 => prop::sample::select(vec!["4294967295", "4294967296", "99999999999", "d99999999999"])
+            .prop_map(String::from),
+        8 => prop::sample::select(vec![
+            "d", "kh", "kl", "dh", "dl", "k", "r", "rr", "!", "!!", "s", "f", "a", "(", ")", "[",
+            "]", "+", "-", "*", "/", "/^", "/~", "<", "<=", ">", ">=", "=", "==", "?", ":", ",",
+            "vs", "adv", "dis", "attack", "x", "%", " ",
+        ])
+        .prop_map(String::from),
+    ]
+}
+
+/// Pieces put together at random, so they also make up new tokens: `d` and `6` make `d6`
+fn soup() -> impl Strategy<Value = String> {
+    prop::collection::vec(piece(), 0..16).prop_map(|pieces| pieces.concat())
+}
+
+/// Small enough for a test to run every case in no time
+const LIMITS: Limits = Limits {
+    max_dice: 100,
+    max_faces: 1_000,
+    max_support: 10_000,
+    max_operations: 1_000_000,
+};
+
+/// Parses `expr`, and throws and analyzes it within the [`LIMITS`]
+fn run(expr: &str) -> Result<(), TestCaseError> {
+    let hand = match Hand::parse_with(expr, &LIMITS) {
+        Ok(hand) => hand,
+        Err(errors) => {
+            prop_assert!(!errors.is_empty());
+            let len = expr.chars().count();
+            for e in errors {
+                prop_assert!(e.start <= e.end && e.end <= len, "{:?} in {:?}", e, expr);
+            }
+            return Ok(());
+        }
+    };
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    for _ in 0..10 {
+        let _ = hand.throw_with(&mut rng);
+    }
+    let _ = hand.analyze_with::<f64>(&AnalyzeOptions { explosion_depth: 2 });
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2_000))]
+
+    #[test]
+    fn any_string(expr in ".{0,32}") {
+        run(&expr)?;
+    }
+
+    #[test]
+    fn expression_soup(expr in soup()) {
+        run(&expr)?;
+    }
+
+    /// Spaces between the tokens are left out of them, so more of them don't change the errors
+    #[test]
+    fn parse_ignores_whitespace(expr in soup()) {
+        let kinds = |expr: &str| {
+            Hand::parse_with(expr, &LIMITS).map(|_| ()).map_err(|errors| {
+                errors.into_iter().map(|e| e.kind).collect::<Vec<_>>()
+            })
+        };
+        prop_assert_eq!(kinds(&expr), kinds(&expr.replace("  ", " ")));
+    }
+}
+
+/// Inputs that crashed before they were fixed
+#[test]
+fn regressions() {
+    for expr in &[
+        "d99999999999",
+        "99999999999d6",
+        "4d6kh99999999999",
+        "d6r<99999999999",
+        "d6!>4294967295",
+        "d10s>=99999999999",
+        "4294967295 * 4294967295 * 4294967295",
+        "0 - 4294967295 * 4294967295 * 4294967295",
+        "1000000d1000000",
+        "(4294967295)d6",
+        "(d100)d(d100)d1000",
+        "d1000 * d1000 * d1000",
+        "d20!!100",
+    ] {
+        run(expr).unwrap();
+    }
+}